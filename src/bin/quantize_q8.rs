@@ -1,53 +1,502 @@
 //! CLI interface for Q8K quantization with advanced strategies.
+//!
+//! Every flag falls back to its `CANDLE_Q8K_*` environment variable, and the subcommand
+//! may be left out for `quantize`, so `quantize_q8k <input> <output_dir>` keeps working.
 
 use anyhow::{Context, Result};
-use quantize_strategy::{quantize_safetensors, QuantizationConfig, StrategyType};
+use clap::builder::BoolishValueParser;
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use quantize_strategy::core::{
+    dequantize_model, inspect_perm, inspect_quantized, parse_thresholds, verify_quantized,
+    DequantizeConfig, MetricThresholds, REPORT_JSON,
+};
+use quantize_strategy::strategies::{compare_strategies, registered_strategies, CompareConfig};
+use quantize_strategy::{
+    quantize_safetensors, AutoStrategy, GateMode, GptqConfig, MonteCarloConfig, OutputLayout,
+    QualityGateError, QuantFormat, QuantizationConfig, StrategyType, ValidationConfig,
+    ValidationMetric,
+};
+use safetensors::tensor::Dtype;
 use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
 
-fn main() -> Result<()> {
-    // Parse arguments
-    let mut args = std::env::args().skip(1);
-    let in_file: PathBuf = args
-        .next()
-        .context("Usage: quantize_q8k <input.safetensors> <output_dir>")?
-        .into();
-    let out_dir: PathBuf = args
-        .next()
-        .context("Usage: quantize_q8k <input.safetensors> <output_dir>")?
-        .into();
-
-    // Configuration from environment
-    let use_permutation = std::env::var("CANDLE_Q8K_PERMUTE")
-        .ok()
-        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
-        .unwrap_or(false);
-
-    let strategy_name =
-        std::env::var("CANDLE_Q8K_STRATEGY").unwrap_or_else(|_| "l2_norm".to_string());
+/// Exit status when the run completed but failed its quality gates.
+const EXIT_QUALITY_GATE: u8 = 2;
 
-    let strategy_type = match strategy_name.as_str() {
+const SUBCOMMANDS: &[&str] = &[
+    "quantize",
+    "verify",
+    "inspect",
+    "dequantize",
+    "compare",
+    "help",
+];
+const STRATEGIES: &[&str] = &[
+    "identity",
+    "l2_norm",
+    "attention_aware",
+    "qr_pivot",
+    "learnable",
+    "activation_aware",
+    "auto",
+];
+const FORMATS: &[&str] = &["q2k", "q3k", "q4k", "q5k", "q6k", "q8k"];
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().collect();
+    // Bare `quantize_q8k <input> <output_dir>` predates the subcommands
+    if args
+        .get(1)
+        .is_some_and(|a| !a.starts_with('-') && !SUBCOMMANDS.contains(&a.as_str()))
+    {
+        args.insert(1, "quantize".to_string());
+    }
+    let matches = match cli().try_get_matches_from(args) {
+        Ok(matches) => matches,
+        Err(e) => {
+            let _ = e.print();
+            return if e.use_stderr() {
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            };
+        }
+    };
+    let result = match matches.subcommand() {
+        Some(("quantize", m)) => run_quantize(m),
+        Some(("verify", m)) => run_verify(m),
+        Some(("inspect", m)) => run_inspect(m),
+        Some(("dequantize", m)) => run_dequantize(m),
+        Some(("compare", m)) => run_compare(m),
+        _ => unreachable!("subcommand is required"),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) if e.is::<QualityGateError>() => {
+            eprintln!("Error: {e}");
+            ExitCode::from(EXIT_QUALITY_GATE)
+        }
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn cli() -> Command {
+    Command::new("quantize_q8k")
+        .about("Quantize safetensors or GGUF models to k-quant blocks with column permutations")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("quantize")
+                .about("Quantize a model into per-tensor .q8k files or one GGUF file")
+                .arg(input_arg())
+                .arg(
+                    Arg::new("output")
+                        .value_name("OUTPUT_DIR")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Directory for the quantized tensors and the report"),
+                )
+                .arg(flag(
+                    "permute",
+                    "CANDLE_Q8K_PERMUTE",
+                    "Permute columns before quantizing",
+                ))
+                .arg(
+                    Arg::new("strategy")
+                        .long("strategy")
+                        .env("CANDLE_Q8K_STRATEGY")
+                        .value_name("NAME")
+                        .value_parser(STRATEGIES.to_vec())
+                        .default_value("l2_norm")
+                        .help("Column ordering strategy"),
+                )
+                .arg(
+                    Arg::new("auto-candidates")
+                        .long("auto-candidates")
+                        .env("CANDLE_Q8K_AUTO_CANDIDATES")
+                        .value_name("LIST")
+                        .value_delimiter(',')
+                        .value_parser(STRATEGIES[..STRATEGIES.len() - 1].to_vec())
+                        .help(
+                            "Candidates of the auto strategy [default: identity,l2_norm,qr_pivot]",
+                        ),
+                )
+                .arg(
+                    Arg::new("auto-budget")
+                        .long("auto-budget")
+                        .env("CANDLE_Q8K_AUTO_BUDGET")
+                        .value_name("SECONDS")
+                        .value_parser(|s: &str| {
+                            s.parse::<f64>()
+                                .ok()
+                                .and_then(|s| Duration::try_from_secs_f64(s).ok())
+                                .ok_or("expected a non-negative number of seconds")
+                        })
+                        .help("Time per tensor after which auto starts no new candidate"),
+                )
+                .arg(calibration_arg())
+                .arg(
+                    Arg::new("gptq")
+                        .long("gptq")
+                        .env("CANDLE_Q8K_GPTQ")
+                        .value_name("FILE")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Hessians or calibration inputs for GPTQ error compensation"),
+                )
+                .arg(format_arg())
+                .arg(flag(
+                    "gguf",
+                    "CANDLE_Q8K_GGUF",
+                    "Write one <model>.gguf instead of per-tensor files",
+                ))
+                .arg(skip_arg())
+                .arg(
+                    Arg::new("memory-budget")
+                        .long("memory-budget")
+                        .env("CANDLE_Q8K_MEMORY_BUDGET")
+                        .value_name("SIZE")
+                        .value_parser(|s: &str| parse_size(s).map_err(|e| e.to_string()))
+                        .help("Fail up front if a tensor's working set exceeds this, e.g. 48G"),
+                )
+                .arg(
+                    Arg::new("threads")
+                        .long("threads")
+                        .env("CANDLE_Q8K_THREADS")
+                        .value_name("N")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .help("Tensors quantized in parallel [default: all cores]"),
+                )
+                .args(validation_args())
+                .arg(
+                    Arg::new("report")
+                        .long("no-report")
+                        .env("CANDLE_Q8K_REPORT")
+                        .action(ArgAction::SetFalse)
+                        .value_parser(BoolishValueParser::new())
+                        .help("Skip the JSON/CSV report"),
+                )
+                .arg(flag(
+                    "baseline",
+                    "CANDLE_Q8K_BASELINE",
+                    "Compare against unpermuted rounding, drop unhelpful permutations",
+                )),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Validate per-tensor output against its source model")
+                .arg(input_arg())
+                .arg(
+                    Arg::new("output")
+                        .value_name("OUTPUT_DIR")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Directory with .q8k files and their sidecars"),
+                )
+                .args(validation_args()),
+        )
+        .subcommand(
+            Command::new("inspect")
+                .about("Check .q8k and .perm files and summarize their contents")
+                .arg(
+                    Arg::new("files")
+                        .value_name("FILE")
+                        .required(true)
+                        .num_args(1..)
+                        .value_parser(value_parser!(PathBuf))
+                        .help(".q8k files (their .perm and .scale sidecars are included) or .perm files"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the findings as JSON"),
+                ),
+        )
+        .subcommand(
+            Command::new("dequantize")
+                .about("Rebuild a safetensors model from per-tensor or GGUF output")
+                .arg(
+                    Arg::new("quantized")
+                        .value_name("QUANTIZED")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Directory with .q8k files and their sidecars, or a GGUF file"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .value_name("OUTPUT")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Safetensors file to write"),
+                )
+                .arg(
+                    Arg::new("source")
+                        .long("source")
+                        .env("CANDLE_Q8K_SOURCE")
+                        .value_name("MODEL")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Model the output was quantized from; its unquantized tensors are copied over"),
+                )
+                .arg(
+                    Arg::new("dtype")
+                        .long("dtype")
+                        .env("CANDLE_Q8K_DEQUANTIZE_DTYPE")
+                        .value_name("DTYPE")
+                        .value_parser(["f16", "bf16", "f32"])
+                        .default_value("f32")
+                        .help("Element type of the dequantized tensors"),
+                )
+                .arg(skip_arg()),
+        )
+        .subcommand(
+            Command::new("compare")
+                .about("Run several strategies on the same tensors and compare their error")
+                .arg(input_arg())
+                .arg(
+                    Arg::new("json")
+                        .value_name("REPORT_JSON")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Also write the comparison as JSON"),
+                )
+                .arg(
+                    Arg::new("strategies")
+                        .long("strategies")
+                        .env("CANDLE_Q8K_COMPARE_STRATEGIES")
+                        .value_name("LIST")
+                        .value_delimiter(',')
+                        .value_parser(STRATEGIES.to_vec())
+                        .help("Strategies to compare [default: all]"),
+                )
+                .arg(
+                    Arg::new("sample")
+                        .long("sample")
+                        .env("CANDLE_Q8K_COMPARE_SAMPLE")
+                        .value_name("N")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .help("Compare on N tensors spread evenly through the model"),
+                )
+                .arg(calibration_arg())
+                .arg(format_arg())
+                .arg(skip_arg()),
+        )
+}
+
+fn input_arg() -> Arg {
+    Arg::new("input")
+        .value_name("INPUT")
+        .required(true)
+        .value_parser(value_parser!(PathBuf))
+        .help("Model: .safetensors, .gguf, a sharded checkpoint directory or its index")
+}
+
+/// A switch that can also be set with `env=1` or `env=0`; other non-boolean values are
+/// rejected.
+fn flag(name: &'static str, env: &'static str, help: &'static str) -> Arg {
+    Arg::new(name)
+        .long(name)
+        .env(env)
+        .action(ArgAction::SetTrue)
+        .value_parser(BoolishValueParser::new())
+        .help(help)
+}
+
+fn calibration_arg() -> Arg {
+    Arg::new("calibration")
+        .long("calibration")
+        .env("CANDLE_Q8K_CALIBRATION")
+        .value_name("FILE")
+        .value_parser(value_parser!(PathBuf))
+        .help("Activation statistics for the activation_aware strategy")
+}
+
+fn format_arg() -> Arg {
+    Arg::new("format")
+        .long("format")
+        .env("CANDLE_Q8K_FORMAT")
+        .value_name("FORMAT")
+        .value_parser(FORMATS.to_vec())
+        .default_value("q8k")
+        .help("Output block format")
+}
+
+fn skip_arg() -> Arg {
+    Arg::new("skip")
+        .long("skip")
+        .env("CANDLE_Q8K_SKIP")
+        .value_name("PATTERNS")
+        .value_delimiter(',')
+        .action(ArgAction::Append)
+        .help("Name substrings of tensors to leave unquantized [default: embed_tokens,token_embd,norm]")
+}
+
+fn validation_args() -> Vec<Arg> {
+    let thresholds = |s: &str| parse_thresholds(s).map_err(|e| format!("{e:#}"));
+    vec![
+        Arg::new("validation")
+            .long("validation")
+            .env("CANDLE_Q8K_VALIDATION")
+            .value_name("SPEC")
+            .value_parser(|s: &str| ValidationConfig::from_spec(s).map_err(|e| format!("{e:#}")))
+            .help("0 to skip validation, or the metrics to run, e.g. mse,sqnr,cosine"),
+        Arg::new("monte-carlo")
+            .long("monte-carlo")
+            .env("CANDLE_Q8K_MONTE_CARLO")
+            .value_name("SPEC")
+            .value_parser(|s: &str| MonteCarloConfig::from_spec(s).map_err(|e| format!("{e:#}")))
+            .help("Monte-Carlo inputs: gaussian, heavy_tailed or a calibration file, e.g. gaussian:64"),
+        Arg::new("thresholds")
+            .long("thresholds")
+            .env("CANDLE_Q8K_THRESHOLDS")
+            .value_name("SPEC")
+            .value_parser(thresholds)
+            .help("Per-tensor thresholds, e.g. matmul=1e-3,down_proj:sqnr=25"),
+        Arg::new("global-thresholds")
+            .long("global-thresholds")
+            .env("CANDLE_Q8K_GLOBAL_THRESHOLDS")
+            .value_name("SPEC")
+            .value_parser(|s: &str| match parse_thresholds(s) {
+                Ok((thresholds, overrides)) if overrides.is_empty() => Ok(thresholds),
+                Ok(_) => Err("global thresholds take no tensor patterns".to_string()),
+                Err(e) => Err(format!("{e:#}")),
+            })
+            .help("Thresholds on the model-wide mean, e.g. mse=1e-5"),
+        Arg::new("gate")
+            .long("gate")
+            .env("CANDLE_Q8K_GATE")
+            .value_name("MODE")
+            .value_parser(["warn", "fail"])
+            .default_value("warn")
+            .help("What breached thresholds do; fail exits with status 2"),
+    ]
+}
+
+fn validation_from(m: &ArgMatches) -> ValidationConfig {
+    let mut validation = m
+        .get_one::<ValidationConfig>("validation")
+        .cloned()
+        .unwrap_or_default();
+    if let Some(mc) = m.get_one::<MonteCarloConfig>("monte-carlo") {
+        validation.monte_carlo = mc.clone();
+        if !validation.metrics.contains(&ValidationMetric::MonteCarlo) {
+            validation.metrics.push(ValidationMetric::MonteCarlo);
+        }
+    }
+    if let Some((thresholds, overrides)) = m.get_one("thresholds").cloned() {
+        validation.thresholds = thresholds;
+        validation.threshold_overrides = overrides;
+    }
+    if let Some(thresholds) = m.get_one::<MetricThresholds>("global-thresholds") {
+        validation.global_thresholds = thresholds.clone();
+    }
+    validation.gate = GateMode::from_name(m.get_one::<String>("gate").unwrap()).unwrap();
+    validation
+}
+
+fn format_from(m: &ArgMatches) -> QuantFormat {
+    QuantFormat::from_name(m.get_one::<String>("format").unwrap()).unwrap()
+}
+
+fn skip_patterns_from(m: &ArgMatches) -> Option<Vec<String>> {
+    m.get_many::<String>("skip")
+        .map(|patterns| patterns.filter(|p| !p.is_empty()).cloned().collect())
+}
+
+/// Strategy with its default parameters; `auto` gets the default candidates.
+fn strategy_from_name(name: &str, calibration: Option<&PathBuf>) -> Result<StrategyType> {
+    Ok(match name {
+        "identity" => StrategyType::Identity,
+        "l2_norm" => StrategyType::L2Norm,
         "attention_aware" => StrategyType::AttentionAware,
         "qr_pivot" => StrategyType::QRPivot,
         "learnable" => StrategyType::Learnable {
             learning_rate: 0.01,
             iterations: 1000,
         },
-        _ => StrategyType::L2Norm,
+        "activation_aware" => StrategyType::ActivationAware {
+            calibration_path: calibration
+                .context("the activation_aware strategy requires --calibration")?
+                .clone(),
+        },
+        "auto" => StrategyType::Auto {
+            candidates: AutoStrategy::default_candidates(),
+            time_budget: None,
+        },
+        _ => unreachable!("strategy names are checked by the parser"),
+    })
+}
+
+fn run_quantize(m: &ArgMatches) -> Result<()> {
+    let in_file = m.get_one::<PathBuf>("input").unwrap().clone();
+    let out_dir = m.get_one::<PathBuf>("output").unwrap().clone();
+    let use_permutation = m.get_flag("permute");
+    let strategy_name = m.get_one::<String>("strategy").unwrap();
+    let calibration = m.get_one::<PathBuf>("calibration");
+
+    let strategy_type = match strategy_name.as_str() {
+        "auto" => StrategyType::Auto {
+            candidates: match m.get_many::<String>("auto-candidates") {
+                Some(names) => names
+                    .map(|n| strategy_from_name(n, calibration))
+                    .collect::<Result<Vec<_>>>()?,
+                None => AutoStrategy::default_candidates(),
+            },
+            time_budget: m.get_one::<Duration>("auto-budget").copied(),
+        },
+        name => strategy_from_name(name, calibration)?,
     };
 
-    let config = QuantizationConfig {
+    let gptq = m.get_one::<PathBuf>("gptq").map(GptqConfig::new);
+    let format = format_from(m);
+    let output_layout = if m.get_flag("gguf") {
+        OutputLayout::Gguf
+    } else {
+        OutputLayout::PerTensor
+    };
+    let memory_budget = m.get_one::<u64>("memory-budget").copied();
+    let threads = m.get_one::<u64>("threads").map(|&n| n as usize);
+    let validation = validation_from(m);
+    let report = m.get_flag("report");
+
+    let mut config = QuantizationConfig {
         strategy_type,
         use_permutation,
         output_dir: out_dir.clone(),
         attention_aware: strategy_name == "attention_aware",
+        gptq: gptq.clone(),
+        format,
+        output_layout,
+        memory_budget,
+        threads,
+        validation: validation.clone(),
+        report,
+        compare_baseline: m.get_flag("baseline"),
         ..Default::default()
     };
+    if let Some(patterns) = skip_patterns_from(m) {
+        config.skip_patterns = patterns;
+    }
 
     // Print configuration
     println!("Input  : {}", in_file.display());
     println!("Output : {}", out_dir.display());
     println!("Permute: {}", if use_permutation { "on" } else { "off" });
     println!("Strategy: {}", strategy_name);
+    println!("Format : {}", format.name());
+    if let Some(ref gptq) = gptq {
+        println!("GPTQ   : {}", gptq.calibration_path.display());
+    }
+    if !validation.enabled || validation.metrics.is_empty() {
+        println!("Validation: off");
+    } else if validation.gate == GateMode::Fail {
+        println!("Gates  : fail on breach");
+    }
+    if let Some(budget) = memory_budget {
+        println!("Budget : {} MiB", budget >> 20);
+    }
 
     // Run quantization
     let result = quantize_safetensors(&in_file, config)?;
@@ -57,16 +506,159 @@ fn main() -> Result<()> {
         "Done in {:.2}s. Quantized: {}, skipped: {}",
         result.total_time_seconds, result.quantized_tensors, result.skipped_tensors
     );
+    if let Some(rss) = result.peak_rss_bytes {
+        println!("Peak RSS: {} MiB", rss >> 20);
+    }
+    if report {
+        println!("Report : {}", out_dir.join(REPORT_JSON).display());
+    }
+    if !result.gate_failures.is_empty() {
+        println!(
+            "Quality gates: {} breach(es), warn only",
+            result.gate_failures.len()
+        );
+    }
 
-    if !result.mse_stats.is_empty() {
-        println!("\nMSE Statistics:");
-        for (name, mse_matmul, mse_direct) in result.mse_stats.iter() {
-            println!(
-                "  {}: matmul={:.6e}, direct={:.6e}",
-                name, mse_matmul, mse_direct
-            );
+    let validated: Vec<_> = result.metrics().filter(|m| m.validated_rows > 0).collect();
+    if !validated.is_empty() {
+        println!("\nValidation:");
+        for m in validated {
+            println!("  {}: {}", m.name, m.summary());
         }
     }
 
     Ok(())
 }
+
+fn run_verify(m: &ArgMatches) -> Result<()> {
+    let in_file = m.get_one::<PathBuf>("input").unwrap();
+    let out_dir = m.get_one::<PathBuf>("output").unwrap();
+    let validation = validation_from(m);
+
+    println!("Input  : {}", in_file.display());
+    println!("Output : {}", out_dir.display());
+    let result = verify_quantized(in_file, out_dir, &validation)?;
+    println!("Verified {} tensor(s)", result.tensors.len());
+    if !result.gate_failures.is_empty() {
+        println!(
+            "Quality gates: {} breach(es), warn only",
+            result.gate_failures.len()
+        );
+    }
+    Ok(())
+}
+
+/// Fails when any file has problems, so scripts can use the exit status.
+fn run_inspect(m: &ArgMatches) -> Result<()> {
+    let json = m.get_flag("json");
+    let mut reports = Vec::new();
+    let mut failing = 0;
+    for path in m.get_many::<PathBuf>("files").unwrap() {
+        let report = if path.extension().is_some_and(|e| e == "perm") {
+            let perm = inspect_perm(path)?;
+            failing += usize::from(!perm.problems.is_empty());
+            if !json {
+                print!("{perm}");
+            }
+            serde_json::to_value(perm)?
+        } else {
+            let file = inspect_quantized(path)?;
+            failing += usize::from(!file.is_ok());
+            if !json {
+                print!("{file}");
+            }
+            serde_json::to_value(file)?
+        };
+        reports.push(report);
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    }
+    if failing > 0 {
+        anyhow::bail!("{failing} file(s) with problems");
+    }
+    Ok(())
+}
+
+fn run_dequantize(m: &ArgMatches) -> Result<()> {
+    let quantized = m.get_one::<PathBuf>("quantized").unwrap();
+    let output = m.get_one::<PathBuf>("output").unwrap();
+    let mut config = DequantizeConfig {
+        dtype: match m.get_one::<String>("dtype").unwrap().as_str() {
+            "f16" => Dtype::F16,
+            "bf16" => Dtype::BF16,
+            _ => Dtype::F32,
+        },
+        source: m.get_one::<PathBuf>("source").cloned(),
+        ..Default::default()
+    };
+    if let Some(patterns) = skip_patterns_from(m) {
+        config.skip_patterns = patterns;
+    }
+
+    println!("Input  : {}", quantized.display());
+    if let Some(ref source) = config.source {
+        println!("Source : {}", source.display());
+    }
+    println!("Output : {}", output.display());
+    let result = dequantize_model(quantized, output, &config)?;
+    println!(
+        "Wrote {} dequantized and {} passthrough tensor(s), {:.2} MB",
+        result.dequantized_tensors,
+        result.passthrough_tensors,
+        result.file_size as f64 / 1e6
+    );
+    Ok(())
+}
+
+fn run_compare(m: &ArgMatches) -> Result<()> {
+    let in_file = m.get_one::<PathBuf>("input").unwrap();
+    let json_out = m.get_one::<PathBuf>("json");
+    let calibration = m.get_one::<PathBuf>("calibration");
+    let strategies = match m.get_many::<String>("strategies") {
+        Some(names) => names
+            .map(|n| strategy_from_name(n, calibration))
+            .collect::<Result<Vec<_>>>()?,
+        None => registered_strategies(calibration.map(PathBuf::as_path)),
+    };
+    let mut config = CompareConfig {
+        strategies,
+        format: format_from(m),
+        sample_tensors: m.get_one::<u64>("sample").map(|&n| n as usize),
+        ..Default::default()
+    };
+    if let Some(patterns) = skip_patterns_from(m) {
+        config.skip_patterns = patterns;
+    }
+
+    println!("Input  : {}", in_file.display());
+    println!("Format : {}", config.format.name());
+    let comparison = compare_strategies(in_file, &config)?;
+    println!(
+        "\nCompared {} tensor(s)\n{}",
+        comparison.tensors.len(),
+        comparison.to_table()
+    );
+    if let Some(path) = json_out {
+        std::fs::write(path, comparison.to_json()?)
+            .with_context(|| format!("writing {}", path.display()))?;
+        println!("Report : {}", path.display());
+    }
+    Ok(())
+}
+
+/// Parse a byte count with an optional `K`, `M`, `G` or `T` (binary) suffix, e.g. `48G`.
+fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (digits, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        Some('T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let value: u64 = digits.trim().parse()?;
+    value
+        .checked_mul(1 << shift)
+        .context("memory budget overflows u64")
+}
@@ -0,0 +1,211 @@
+//! Calibration statistics loaded from safetensors files.
+//!
+//! Activation statistics are stored as 1-D tensors of length `k` (one value per input
+//! channel) keyed by module name, e.g. `model.layers.0.mlp.up_proj.act_mean` and
+//! `model.layers.0.mlp.up_proj.act_max` for the weight `model.layers.0.mlp.up_proj.weight`.
+//! Hessian proxies use the same keying: `<module>.hessian` holds `X^T X` as a `k x k` tensor,
+//! `<module>.inputs` holds raw calibration inputs `X` as `n x k`.
+//!
+//! Calibration files are memory-mapped like the input model; a Hessian or input tensor is
+//! decoded when its layer is quantized and its pages are released afterwards.
+
+use super::source::{map_file, release_pages};
+use crate::utils::tensor_to_f32;
+use anyhow::{bail, Context, Result};
+use memmap2::Mmap;
+use safetensors::tensor::TensorView;
+use safetensors::SafeTensors;
+use std::collections::HashMap;
+use std::path::Path;
+
+pub const ACT_MEAN_SUFFIX: &str = "act_mean";
+pub const ACT_MAX_SUFFIX: &str = "act_max";
+pub const HESSIAN_SUFFIX: &str = "hessian";
+pub const INPUTS_SUFFIX: &str = "inputs";
+
+/// Key of the `suffix` statistic of a weight: `<module>.<suffix>` for `<module>.weight`.
+fn calibration_key(tensor_name: &str, suffix: &str) -> String {
+    let module = tensor_name.strip_suffix(".weight").unwrap_or(tensor_name);
+    format!("{module}.{suffix}")
+}
+
+/// A memory-mapped calibration safetensors file.
+struct CalibrationFile {
+    map: Mmap,
+}
+
+impl CalibrationFile {
+    fn open(path: &Path) -> Result<Self> {
+        let map = map_file(path)
+            .with_context(|| format!("reading calibration file {}", path.display()))?;
+        SafeTensors::deserialize(&map)
+            .with_context(|| format!("parsing calibration file {}", path.display()))?;
+        Ok(Self { map })
+    }
+
+    fn tensors(&self) -> Result<SafeTensors<'_>> {
+        Ok(SafeTensors::deserialize(&self.map)?)
+    }
+
+    /// The `suffix` statistic of the weight `tensor_name`, if the file holds one.
+    fn lookup(&self, tensor_name: &str, suffix: &str) -> Result<Option<TensorView<'_>>> {
+        Ok(self
+            .tensors()?
+            .tensor(&calibration_key(tensor_name, suffix))
+            .ok())
+    }
+
+    /// Drop the resident pages of a decoded tensor.
+    fn release(&self, tensor: &TensorView<'_>) {
+        release_pages(&self.map, tensor.data());
+    }
+}
+
+/// Per-input-channel activation statistics for every calibrated layer.
+#[derive(Debug, Clone, Default)]
+pub struct ActivationStats {
+    channels: HashMap<String, Vec<f32>>,
+}
+
+impl ActivationStats {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = CalibrationFile::open(path)?;
+        let st = file.tensors()?;
+        let mut channels = HashMap::new();
+        for name in st.names() {
+            let tensor = st.tensor(name)?;
+            if tensor.shape().len() != 1 {
+                continue;
+            }
+            channels.insert(
+                name.to_string(),
+                tensor_to_f32(tensor.data(), tensor.dtype())?,
+            );
+        }
+        Ok(Self { channels })
+    }
+
+    /// Mean absolute activation per input channel of the given weight tensor.
+    pub fn channel_means(&self, tensor_name: &str) -> Option<&[f32]> {
+        self.lookup(tensor_name, ACT_MEAN_SUFFIX)
+    }
+
+    /// Max absolute activation per input channel of the given weight tensor.
+    pub fn channel_maxes(&self, tensor_name: &str) -> Option<&[f32]> {
+        self.lookup(tensor_name, ACT_MAX_SUFFIX)
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    fn lookup(&self, tensor_name: &str, suffix: &str) -> Option<&[f32]> {
+        self.channels
+            .get(&calibration_key(tensor_name, suffix))
+            .map(|v| v.as_slice())
+    }
+}
+
+/// Per-layer Hessian proxies, decoded lazily since each one is `k x k`.
+pub struct HessianStore {
+    file: CalibrationFile,
+}
+
+impl HessianStore {
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self {
+            file: CalibrationFile::open(path)?,
+        })
+    }
+
+    /// `X^T X` for the given weight tensor in original column order, row-major `k x k`.
+    pub fn hessian(&self, tensor_name: &str, k: usize) -> Result<Option<Vec<f64>>> {
+        if let Some(tensor) = self.file.lookup(tensor_name, HESSIAN_SUFFIX)? {
+            if tensor.shape() != [k, k] {
+                bail!(
+                    "hessian for {} has shape {:?}, expected [{}, {}]",
+                    tensor_name,
+                    tensor.shape(),
+                    k,
+                    k
+                );
+            }
+            let h = tensor_to_f32(tensor.data(), tensor.dtype())?;
+            self.file.release(&tensor);
+            return Ok(Some(h.into_iter().map(|v| v as f64).collect()));
+        }
+
+        if let Some(tensor) = self.file.lookup(tensor_name, INPUTS_SUFFIX)? {
+            let shape = tensor.shape();
+            if shape.len() != 2 || shape[1] != k {
+                bail!(
+                    "calibration inputs for {} have shape {:?}, expected [n, {}]",
+                    tensor_name,
+                    shape,
+                    k
+                );
+            }
+            let x = tensor_to_f32(tensor.data(), tensor.dtype())?;
+            self.file.release(&tensor);
+            let mut h = vec![0f64; k * k];
+            for row in x.chunks_exact(k) {
+                for i in 0..k {
+                    let xi = row[i] as f64;
+                    if xi == 0.0 {
+                        continue;
+                    }
+                    for j in i..k {
+                        h[i * k + j] += xi * row[j] as f64;
+                    }
+                }
+            }
+            for i in 0..k {
+                for j in 0..i {
+                    h[i * k + j] = h[j * k + i];
+                }
+            }
+            return Ok(Some(h));
+        }
+
+        Ok(None)
+    }
+}
+
+/// Raw calibration inputs (`<module>.inputs`, `n x k`), used to drive validation with real
+/// activations.
+pub struct CalibrationInputs {
+    file: CalibrationFile,
+}
+
+impl CalibrationInputs {
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self {
+            file: CalibrationFile::open(path)?,
+        })
+    }
+
+    /// Up to `max_rows` input vectors of the given weight tensor, row-major `n x k`.
+    pub fn inputs(&self, tensor_name: &str, k: usize, max_rows: usize) -> Result<Option<Vec<f32>>> {
+        let Some(tensor) = self.file.lookup(tensor_name, INPUTS_SUFFIX)? else {
+            return Ok(None);
+        };
+        let shape = tensor.shape();
+        if shape.len() != 2 || shape[1] != k {
+            bail!(
+                "calibration inputs for {} have shape {:?}, expected [n, {}]",
+                tensor_name,
+                shape,
+                k
+            );
+        }
+        let rows = shape[0].min(max_rows);
+        let row_bytes = tensor.data().len() / shape[0].max(1);
+        let x = tensor_to_f32(&tensor.data()[..rows * row_bytes], tensor.dtype())?;
+        self.file.release(&tensor);
+        Ok(Some(x))
+    }
+}
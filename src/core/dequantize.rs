@@ -0,0 +1,323 @@
+//! Reconstruction of a plain safetensors model from quantized output.
+//!
+//! Each quantized tensor is decoded to f32, its columns are put back in source order (the
+//! inverse of its permutation) and divided by their per-input-channel scales, then stored as
+//! F16, BF16 or F32 under its original name. Tensors that were not quantized are copied from
+//! the source model, so the result loads like the original in tools that cannot read `.q8k`.
+//! A source tensor that the skip patterns would have quantized but that has no quantized
+//! counterpart is an error rather than a silent full-precision copy.
+
+use super::io::{load_perm, load_quantized, load_scales, read_q8k_header};
+use super::{skip_reason, ModelSource, QuantFormat, QuantizationConfig};
+use crate::utils::f32_to_tensor;
+use anyhow::{bail, Context, Result};
+use candle_core::quantized::gguf_file::Value;
+use candle_core::quantized::k_quants::{
+    BlockQ2K, BlockQ3K, BlockQ4K, BlockQ5K, BlockQ6K, BlockQ8K,
+};
+use candle_core::quantized::{GgmlDType, GgmlType};
+use safetensors::tensor::{Dtype, View};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct DequantizeConfig {
+    /// Element type of the dequantized tensors: F16, BF16 or F32. Passthrough tensors keep
+    /// the type they have in the source.
+    pub dtype: Dtype,
+    /// Model the output was quantized from. Its tensors that were not quantized are merged
+    /// into the result; per-tensor output holds none of them, so without a source the
+    /// result has only the quantized tensors.
+    pub source: Option<PathBuf>,
+    /// `QuantizationConfig::skip_patterns` the output was quantized with. A source tensor
+    /// they do not skip must be in the quantized output.
+    pub skip_patterns: Vec<String>,
+}
+
+impl Default for DequantizeConfig {
+    fn default() -> Self {
+        Self {
+            dtype: Dtype::F32,
+            source: None,
+            skip_patterns: QuantizationConfig::default().skip_patterns,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DequantizeResult {
+    pub output: PathBuf,
+    pub dequantized_tensors: usize,
+    pub passthrough_tensors: usize,
+    pub file_size: u64,
+}
+
+/// One tensor of the safetensors output.
+struct OutTensor<'a> {
+    dtype: Dtype,
+    shape: Vec<usize>,
+    data: Cow<'a, [u8]>,
+}
+
+impl View for &OutTensor<'_> {
+    fn dtype(&self) -> Dtype {
+        self.dtype
+    }
+
+    fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.data)
+    }
+
+    fn data_len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Write the tensors of `quantized`, either a directory of `.q8k` files with their sidecars
+/// or a GGUF file written by this crate, to the safetensors file `output`.
+///
+/// Every output tensor is held in memory until the file is written.
+pub fn dequantize_model(
+    quantized: &Path,
+    output: &Path,
+    config: &DequantizeConfig,
+) -> Result<DequantizeResult> {
+    if !matches!(config.dtype, Dtype::F16 | Dtype::BF16 | Dtype::F32) {
+        bail!(
+            "cannot dequantize to {:?}; use F16, BF16 or F32",
+            config.dtype
+        );
+    }
+    let container = if quantized.is_dir() {
+        None
+    } else {
+        let container = ModelSource::open(quantized)?;
+        if !container.is_gguf() {
+            bail!(
+                "{} is neither an output directory nor a GGUF file",
+                quantized.display()
+            );
+        }
+        Some(container)
+    };
+    let source = config
+        .source
+        .as_deref()
+        .map(ModelSource::open)
+        .transpose()?;
+
+    let mut tensors: BTreeMap<String, OutTensor> = BTreeMap::new();
+    let mut dequantized = 0;
+    match container {
+        None => {
+            let mut files: Vec<PathBuf> = fs::read_dir(quantized)?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<std::io::Result<_>>()?;
+            files.retain(|p| p.extension().is_some_and(|e| e == "q8k"));
+            files.sort();
+            if files.is_empty() {
+                bail!("no .q8k files in {}", quantized.display());
+            }
+            for path in files {
+                let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                let (values, rows, k) = decode_file(&path)?;
+                let values = restore_columns(
+                    values,
+                    k,
+                    load_perm(&path)?.as_deref(),
+                    load_scales(&path)?.as_deref(),
+                )
+                .with_context(|| format!("restoring columns of {}", path.display()))?;
+                tensors.insert(
+                    name.into_owned(),
+                    OutTensor {
+                        dtype: config.dtype,
+                        shape: vec![rows, k],
+                        data: Cow::Owned(f32_to_tensor(&values, config.dtype)?),
+                    },
+                );
+                dequantized += 1;
+            }
+        }
+        Some(ref container) => {
+            for tensor in container.tensors() {
+                let data = container.raw(tensor);
+                let out = match tensor.dtype.and_then(float_dtype) {
+                    // Float tensors were passed through when quantizing
+                    Some(dtype) => OutTensor {
+                        dtype,
+                        shape: tensor.shape.clone(),
+                        data: Cow::Borrowed(data),
+                    },
+                    None => {
+                        let &[rows, k] = tensor.shape.as_slice() else {
+                            bail!("{}: quantized tensor is not 2-D", tensor.name);
+                        };
+                        let values = restore_columns(
+                            container.tensor_f32(tensor)?,
+                            k,
+                            container.column_perm(&tensor.name)?.as_deref(),
+                            container.column_scales(&tensor.name)?.as_deref(),
+                        )
+                        .with_context(|| format!("restoring columns of {}", tensor.name))?;
+                        container.release(tensor);
+                        dequantized += 1;
+                        OutTensor {
+                            dtype: config.dtype,
+                            shape: vec![rows, k],
+                            data: Cow::Owned(f32_to_tensor(&values, config.dtype)?),
+                        }
+                    }
+                };
+                tensors.insert(tensor.name.clone(), out);
+            }
+        }
+    }
+
+    if let Some(ref source) = source {
+        for tensor in source.tensors() {
+            if let Some(out) = tensors.get(&tensor.name) {
+                if out.shape != tensor.shape {
+                    bail!(
+                        "{} is {:?} in the quantized output but {:?} in {}",
+                        tensor.name,
+                        out.shape,
+                        tensor.shape,
+                        source.name()
+                    );
+                }
+                continue;
+            }
+            if skip_reason(tensor, &config.skip_patterns).is_none() {
+                bail!(
+                    "{} from {} is not skipped by {:?} but missing from {}; \
+                     pass the skip patterns it was quantized with",
+                    tensor.name,
+                    source.name(),
+                    config.skip_patterns,
+                    quantized.display()
+                );
+            }
+            let dtype = tensor
+                .safetensors_dtype()
+                .or_else(|| tensor.dtype.and_then(float_dtype));
+            let out = match dtype {
+                Some(dtype) => OutTensor {
+                    dtype,
+                    shape: tensor.shape.clone(),
+                    data: Cow::Borrowed(source.raw(tensor)),
+                },
+                None => OutTensor {
+                    dtype: config.dtype,
+                    shape: tensor.shape.clone(),
+                    data: Cow::Owned(f32_to_tensor(&source.tensor_f32(tensor)?, config.dtype)?),
+                },
+            };
+            tensors.insert(tensor.name.clone(), out);
+        }
+    } else if container.is_none() {
+        println!("  no source model given: only the quantized tensors are written");
+    }
+
+    // String metadata of the original model, as carried in the source or the GGUF KV
+    let mut metadata: HashMap<String, String> = container
+        .iter()
+        .chain(&source)
+        .flat_map(|m| m.metadata())
+        .filter_map(|(key, value)| match value {
+            Value::String(s) => Some((key.strip_prefix("safetensors.")?.to_string(), s)),
+            _ => None,
+        })
+        .collect();
+    metadata
+        .entry("format".to_string())
+        .or_insert_with(|| "pt".to_string());
+
+    let count = tensors.len();
+    safetensors::serialize_to_file(tensors.iter(), &Some(metadata), output)
+        .with_context(|| format!("writing {}", output.display()))?;
+    Ok(DequantizeResult {
+        output: output.to_path_buf(),
+        dequantized_tensors: dequantized,
+        passthrough_tensors: count - dequantized,
+        file_size: fs::metadata(output)?.len(),
+    })
+}
+
+/// Values of a `.q8k` file as f32, with its rows and inner dimension.
+fn decode_file(path: &Path) -> Result<(Vec<f32>, usize, usize)> {
+    let hdr = read_q8k_header(path)?;
+    let format = QuantFormat::from_dtype_id(hdr.dtype)
+        .with_context(|| format!("unknown dtype {:#x} in {}", hdr.dtype, path.display()))?;
+    match format {
+        QuantFormat::Q8K => decode::<BlockQ8K>(path),
+        QuantFormat::Q6K => decode::<BlockQ6K>(path),
+        QuantFormat::Q5K => decode::<BlockQ5K>(path),
+        QuantFormat::Q4K => decode::<BlockQ4K>(path),
+        QuantFormat::Q3K => decode::<BlockQ3K>(path),
+        QuantFormat::Q2K => decode::<BlockQ2K>(path),
+    }
+}
+
+fn decode<T: GgmlType>(path: &Path) -> Result<(Vec<f32>, usize, usize)> {
+    let (blocks, rows, k, _) = load_quantized::<T>(path)?;
+    let mut values = vec![0f32; rows * k];
+    T::to_float(&blocks, &mut values);
+    Ok((values, rows, k))
+}
+
+/// Undo the column layout of quantization: stored column `i` is source column `perm[i]`
+/// multiplied by `scales[perm[i]]`.
+fn restore_columns(
+    values: Vec<f32>,
+    k: usize,
+    perm: Option<&[usize]>,
+    scales: Option<&[f32]>,
+) -> Result<Vec<f32>> {
+    if let Some(perm) = perm {
+        let mut seen = vec![false; k];
+        if perm.len() != k
+            || !perm
+                .iter()
+                .all(|&p| p < k && !std::mem::replace(&mut seen[p], true))
+        {
+            bail!("permutation is not a bijection on 0..{k}");
+        }
+    }
+    if let Some(scales) = scales {
+        if scales.len() != k {
+            bail!("{} scales for {k} columns", scales.len());
+        }
+        if let Some(s) = scales.iter().find(|s| !(s.is_finite() && **s > 0.0)) {
+            bail!("scale {s} is not positive");
+        }
+    }
+    if perm.is_none() && scales.is_none() {
+        return Ok(values);
+    }
+    let mut restored = vec![0f32; values.len()];
+    for (src, dst) in values.chunks_exact(k).zip(restored.chunks_exact_mut(k)) {
+        for (i, &v) in src.iter().enumerate() {
+            let col = perm.map_or(i, |p| p[i]);
+            dst[col] = v / scales.map_or(1.0, |s| s[col]);
+        }
+    }
+    Ok(restored)
+}
+
+/// Safetensors type of a ggml float type; `None` for block formats.
+fn float_dtype(dtype: GgmlDType) -> Option<Dtype> {
+    match dtype {
+        GgmlDType::F32 => Some(Dtype::F32),
+        GgmlDType::F16 => Some(Dtype::F16),
+        GgmlDType::BF16 => Some(Dtype::BF16),
+        _ => None,
+    }
+}
@@ -0,0 +1,264 @@
+//! Single-file GGUF output.
+//!
+//! Tensor data is streamed to a side file while quantizing and the GGUF header, whose
+//! tensor table needs every offset up front, is written on `finish`. Tensors may be added in
+//! any order (e.g. by parallel workers); `sort_tensors_by_key` fixes the final layout. The
+//! result loads with candle's `gguf_file::Content::read`.
+
+use anyhow::{bail, Context, Result};
+use candle_core::quantized::gguf_file::Value;
+use candle_core::quantized::{GgmlDType, GgmlType};
+use std::fs;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+pub const GGUF_MAGIC: u32 = 0x4655_4747; // "GGUF"
+pub const GGUF_VERSION: u32 = 3;
+pub const GGUF_ALIGNMENT: u64 = 32;
+
+/// KV key prefix of the column permutation of a tensor (`U32` array).
+pub const GGUF_PERM_PREFIX: &str = "quantize.perm.";
+/// KV key prefix of the per-input-channel scales of a tensor (`F32` array).
+pub const GGUF_SCALE_PREFIX: &str = "quantize.scale.";
+
+struct TensorEntry {
+    name: String,
+    dims: Vec<usize>,
+    dtype: GgmlDType,
+    /// Position and size in the side file, without padding
+    offset: u64,
+    len: u64,
+}
+
+pub struct GgufWriter {
+    path: PathBuf,
+    data_path: PathBuf,
+    data: BufWriter<fs::File>,
+    data_len: u64,
+    tensors: Vec<TensorEntry>,
+    metadata: Vec<(String, Value)>,
+}
+
+impl GgufWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut data_path = path.as_os_str().to_owned();
+        data_path.push(".data");
+        let data_path = PathBuf::from(data_path);
+        let data = BufWriter::new(
+            fs::File::create(&data_path)
+                .with_context(|| format!("creating {}", data_path.display()))?,
+        );
+        Ok(Self {
+            path: path.to_path_buf(),
+            data_path,
+            data,
+            data_len: 0,
+            tensors: Vec::new(),
+            metadata: Vec::new(),
+        })
+    }
+
+    pub fn add_metadata(&mut self, key: impl Into<String>, value: Value) {
+        self.metadata.push((key.into(), value));
+    }
+
+    /// Add a `rows x k` tensor of k-quant blocks.
+    pub fn add_quantized<T: GgmlType>(
+        &mut self,
+        name: &str,
+        rows: usize,
+        k: usize,
+        blocks: &[T],
+    ) -> Result<()> {
+        let raw = unsafe {
+            std::slice::from_raw_parts(blocks.as_ptr() as *const u8, mem::size_of_val(blocks))
+        };
+        self.add_raw(name, &[rows, k], T::DTYPE, raw)
+    }
+
+    /// Add a tensor from raw little-endian bytes; `dims` are in row-major (outer first) order.
+    pub fn add_raw(
+        &mut self,
+        name: &str,
+        dims: &[usize],
+        dtype: GgmlDType,
+        raw: &[u8],
+    ) -> Result<()> {
+        let elems: usize = dims.iter().product();
+        let expect = elems / dtype.block_size() * dtype.type_size();
+        if raw.len() != expect {
+            bail!(
+                "tensor {name}: {} bytes of data, expected {expect} for {dims:?} {dtype:?}",
+                raw.len()
+            );
+        }
+        let offset = self.data_len;
+        self.data.write_all(raw)?;
+        self.data_len += raw.len() as u64;
+        self.tensors.push(TensorEntry {
+            name: name.to_string(),
+            dims: dims.to_vec(),
+            dtype,
+            offset,
+            len: raw.len() as u64,
+        });
+        Ok(())
+    }
+
+    /// Order the tensor table (and the data) by `key` of the tensor names; the sort is stable.
+    pub fn sort_tensors_by_key<K: Ord>(&mut self, mut key: impl FnMut(&str) -> K) {
+        self.tensors.sort_by_cached_key(|t| key(&t.name));
+    }
+
+    pub fn tensor_count(&self) -> usize {
+        self.tensors.len()
+    }
+
+    /// Path of the GGUF file written by `finish`.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write header, metadata (sorted by key) and tensor table, then append the streamed
+    /// tensor data in table order.
+    pub fn finish(mut self) -> Result<()> {
+        self.data.flush()?;
+        drop(self.data);
+        self.metadata.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut w = BufWriter::new(
+            fs::File::create(&self.path)
+                .with_context(|| format!("creating {}", self.path.display()))?,
+        );
+        w.write_all(&GGUF_MAGIC.to_le_bytes())?;
+        w.write_all(&GGUF_VERSION.to_le_bytes())?;
+        w.write_all(&(self.tensors.len() as u64).to_le_bytes())?;
+        w.write_all(&(self.metadata.len() as u64).to_le_bytes())?;
+        for (key, value) in &self.metadata {
+            write_string(&mut w, key)?;
+            w.write_all(&value_type_id(value).to_le_bytes())?;
+            write_value(&mut w, value)?;
+        }
+        let mut offset = 0u64;
+        for t in &self.tensors {
+            write_string(&mut w, &t.name)?;
+            w.write_all(&(t.dims.len() as u32).to_le_bytes())?;
+            // GGUF lists dimensions innermost first.
+            for &d in t.dims.iter().rev() {
+                w.write_all(&(d as u64).to_le_bytes())?;
+            }
+            w.write_all(&ggml_type_id(t.dtype).to_le_bytes())?;
+            w.write_all(&offset.to_le_bytes())?;
+            offset += t.len + padding_for(t.len);
+        }
+        let pos = w.stream_position()?;
+        w.write_all(&vec![0u8; padding_for(pos) as usize])?;
+
+        let mut data = fs::File::open(&self.data_path)?;
+        for t in &self.tensors {
+            data.seek(SeekFrom::Start(t.offset))?;
+            let copied = std::io::copy(&mut (&mut data).take(t.len), &mut w)?;
+            if copied != t.len {
+                bail!(
+                    "tensor data of {} truncated in {}",
+                    t.name,
+                    self.data_path.display()
+                );
+            }
+            w.write_all(&vec![0u8; padding_for(t.len) as usize])?;
+        }
+        w.flush()?;
+        drop(data);
+        fs::remove_file(&self.data_path)?;
+        Ok(())
+    }
+}
+
+fn padding_for(len: u64) -> u64 {
+    (GGUF_ALIGNMENT - len % GGUF_ALIGNMENT) % GGUF_ALIGNMENT
+}
+
+/// GGML tensor type ids as used in GGUF files.
+pub(crate) fn ggml_type_id(dtype: GgmlDType) -> u32 {
+    match dtype {
+        GgmlDType::F32 => 0,
+        GgmlDType::F16 => 1,
+        GgmlDType::Q4_0 => 2,
+        GgmlDType::Q4_1 => 3,
+        GgmlDType::Q5_0 => 6,
+        GgmlDType::Q5_1 => 7,
+        GgmlDType::Q8_0 => 8,
+        GgmlDType::Q8_1 => 9,
+        GgmlDType::Q2K => 10,
+        GgmlDType::Q3K => 11,
+        GgmlDType::Q4K => 12,
+        GgmlDType::Q5K => 13,
+        GgmlDType::Q6K => 14,
+        GgmlDType::Q8K => 15,
+        GgmlDType::BF16 => 30,
+    }
+}
+
+/// Inverse of `ggml_type_id`.
+pub(crate) fn ggml_dtype_from_id(id: u32) -> Option<GgmlDType> {
+    use GgmlDType::*;
+    [
+        F32, F16, BF16, Q4_0, Q4_1, Q5_0, Q5_1, Q8_0, Q8_1, Q2K, Q3K, Q4K, Q5K, Q6K, Q8K,
+    ]
+    .into_iter()
+    .find(|&d| ggml_type_id(d) == id)
+}
+
+fn value_type_id(value: &Value) -> u32 {
+    match value {
+        Value::U8(_) => 0,
+        Value::I8(_) => 1,
+        Value::U16(_) => 2,
+        Value::I16(_) => 3,
+        Value::U32(_) => 4,
+        Value::I32(_) => 5,
+        Value::F32(_) => 6,
+        Value::Bool(_) => 7,
+        Value::String(_) => 8,
+        Value::Array(_) => 9,
+        Value::U64(_) => 10,
+        Value::I64(_) => 11,
+        Value::F64(_) => 12,
+    }
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> Result<()> {
+    w.write_all(&(s.len() as u64).to_le_bytes())?;
+    w.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn write_value<W: Write>(w: &mut W, value: &Value) -> Result<()> {
+    match value {
+        Value::U8(v) => w.write_all(&v.to_le_bytes())?,
+        Value::I8(v) => w.write_all(&v.to_le_bytes())?,
+        Value::U16(v) => w.write_all(&v.to_le_bytes())?,
+        Value::I16(v) => w.write_all(&v.to_le_bytes())?,
+        Value::U32(v) => w.write_all(&v.to_le_bytes())?,
+        Value::I32(v) => w.write_all(&v.to_le_bytes())?,
+        Value::U64(v) => w.write_all(&v.to_le_bytes())?,
+        Value::I64(v) => w.write_all(&v.to_le_bytes())?,
+        Value::F32(v) => w.write_all(&v.to_le_bytes())?,
+        Value::F64(v) => w.write_all(&v.to_le_bytes())?,
+        Value::Bool(v) => w.write_all(&[*v as u8])?,
+        Value::String(v) => write_string(w, v)?,
+        Value::Array(items) => {
+            let elem_type = items.first().map_or(4, value_type_id);
+            if items.iter().any(|v| value_type_id(v) != elem_type) {
+                bail!("gguf arrays must be homogeneous");
+            }
+            w.write_all(&elem_type.to_le_bytes())?;
+            w.write_all(&(items.len() as u64).to_le_bytes())?;
+            for item in items {
+                write_value(w, item)?;
+            }
+        }
+    }
+    Ok(())
+}
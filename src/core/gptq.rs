@@ -0,0 +1,218 @@
+//! GPTQ-style error-compensating quantization for BlockQ8K.
+//!
+//! Columns are quantized left to right, i.e. in permutation order, and each column's
+//! rounding error is pushed onto the columns not yet quantized through the upper Cholesky
+//! factor of the inverse Hessian `(X^T X)^-1`. The Q8K scale of a block is fixed from the
+//! error-updated weights when its first column is reached.
+
+use anyhow::{bail, Result};
+use candle_core::quantized::k_quants::{BlockQ8K, QK_K};
+use candle_core::quantized::GgmlType;
+
+/// Reorder (and rescale) a Hessian given in original column order to match the data that
+/// is actually quantized: `H'[a][b] = H[p_a][p_b] / (s[p_a] * s[p_b])`.
+pub fn permute_hessian(
+    hessian: &[f64],
+    k: usize,
+    perm: Option<&[usize]>,
+    scales: Option<&[f32]>,
+) -> Vec<f64> {
+    let col = |a: usize| perm.map_or(a, |p| p[a]);
+    let scale = |c: usize| scales.map_or(1.0, |s| s[c] as f64);
+    let mut out = vec![0f64; k * k];
+    for a in 0..k {
+        let (ca, sa) = (col(a), scale(col(a)));
+        for b in 0..k {
+            let cb = col(b);
+            out[a * k + b] = hessian[ca * k + cb] / (sa * scale(cb));
+        }
+    }
+    out
+}
+
+/// Quantize `rows x k` weights to BlockQ8K, compensating rounding errors with the Hessian.
+pub fn quantize_rows_q8k_gptq(
+    rows: usize,
+    k: usize,
+    data: &[f32],
+    mut hessian: Vec<f64>,
+    damp_percent: f64,
+) -> Result<Vec<BlockQ8K>> {
+    if !k.is_multiple_of(QK_K) {
+        bail!("inner dim {k} not multiple of {QK_K}");
+    }
+    if hessian.len() != k * k {
+        bail!("hessian has {} entries, expected {}", hessian.len(), k * k);
+    }
+
+    let mut w = data.to_vec();
+    // Inputs that never fire carry no information: pin their diagonal, drop the weights.
+    for j in 0..k {
+        if hessian[j * k + j] <= 0.0 {
+            hessian[j * k + j] = 1.0;
+            for r in 0..rows {
+                w[r * k + j] = 0.0;
+            }
+        }
+    }
+    let mean_diag = (0..k).map(|j| hessian[j * k + j]).sum::<f64>() / k as f64;
+    for j in 0..k {
+        hessian[j * k + j] += damp_percent * mean_diag;
+    }
+    let u = inverse_upper_cholesky(hessian, k)?;
+
+    let blocks_per_row = k / QK_K;
+    let mut blocks = vec![BlockQ8K::zeros(); rows * blocks_per_row];
+    let mut err = vec![0f32; rows * QK_K];
+    let mut qs = vec![[0i8; QK_K]; rows];
+    let mut iscales = vec![0f32; rows];
+
+    for b in 0..blocks_per_row {
+        let (start, end) = (b * QK_K, (b + 1) * QK_K);
+
+        // Same scale rule as BlockQ8K::from_float: the signed max maps to -128.
+        for r in 0..rows {
+            let mut max = 0f32;
+            for &x in &w[r * k + start..r * k + end] {
+                if x.abs() > max.abs() {
+                    max = x;
+                }
+            }
+            iscales[r] = if max == 0.0 { 0.0 } else { -128.0 / max };
+        }
+
+        for j in start..end {
+            let ujj = u[j * k + j] as f32;
+            let urow = &u[j * k + j + 1..j * k + end];
+            for r in 0..rows {
+                let x = w[r * k + j];
+                let iscale = iscales[r];
+                let (q, deq) = if iscale == 0.0 {
+                    (0.0, 0.0)
+                } else {
+                    let q = (x * iscale).round().clamp(-128.0, 127.0);
+                    (q, q / iscale)
+                };
+                qs[r][j - start] = q as i8;
+                let e = (x - deq) / ujj;
+                err[r * QK_K + j - start] = e;
+                let wrow = &mut w[r * k + j + 1..r * k + end];
+                for (wv, &uv) in wrow.iter_mut().zip(urow) {
+                    *wv -= e * uv as f32;
+                }
+            }
+        }
+
+        // Lazy batch update of every later block with this block's errors.
+        if end < k {
+            for r in 0..rows {
+                let wrow = &mut w[r * k + end..(r + 1) * k];
+                for jj in 0..QK_K {
+                    let e = err[r * QK_K + jj];
+                    if e == 0.0 {
+                        continue;
+                    }
+                    let j = start + jj;
+                    let urow = &u[j * k + end..(j + 1) * k];
+                    for (wv, &uv) in wrow.iter_mut().zip(urow) {
+                        *wv -= e * uv as f32;
+                    }
+                }
+            }
+        }
+
+        for r in 0..rows {
+            let d = if iscales[r] == 0.0 {
+                0.0
+            } else {
+                1.0 / iscales[r]
+            };
+            blocks[r * blocks_per_row + b] = block_q8k_from_parts(d, &qs[r]);
+        }
+    }
+
+    Ok(blocks)
+}
+
+/// Upper Cholesky factor `U` of `H^-1` (`H^-1 = U^T U`), computed in place.
+///
+/// With the reversed ("UL") factorisation `H = V V^T`, `V` upper triangular, we get
+/// `H^-1 = V^-T V^-1`, so `U = V^-1`: one factorisation plus one triangular inverse.
+fn inverse_upper_cholesky(mut a: Vec<f64>, k: usize) -> Result<Vec<f64>> {
+    // UL factorisation into the upper triangle, from the last column backwards.
+    for j in (0..k).rev() {
+        let tail = &a[j * k + j + 1..(j + 1) * k];
+        let pivot = a[j * k + j] - tail.iter().map(|v| v * v).sum::<f64>();
+        if pivot <= 0.0 || !pivot.is_finite() {
+            bail!("hessian is not positive definite; increase the GPTQ dampening");
+        }
+        let vjj = pivot.sqrt();
+        a[j * k + j] = vjj;
+        for i in 0..j {
+            let dot: f64 = (j + 1..k).map(|m| a[i * k + m] * a[j * k + m]).sum();
+            a[i * k + j] = (a[i * k + j] - dot) / vjj;
+        }
+    }
+
+    // Invert the upper triangular factor row by row, from the bottom up.
+    let mut acc = vec![0f64; k];
+    for i in (0..k).rev() {
+        acc[i..].fill(0.0);
+        for m in i + 1..k {
+            let vim = a[i * k + m];
+            if vim == 0.0 {
+                continue;
+            }
+            for j in m..k {
+                acc[j] += vim * a[m * k + j];
+            }
+        }
+        let inv = 1.0 / a[i * k + i];
+        a[i * k + i] = inv;
+        for j in i + 1..k {
+            a[i * k + j] = -acc[j] * inv;
+        }
+    }
+    for i in 0..k {
+        a[i * k..i * k + i].fill(0.0);
+    }
+    Ok(a)
+}
+
+// The layout `block_q8k_from_parts` writes through, with no padding.
+const _: () = assert!(std::mem::size_of::<BlockQ8K>() == 4 + QK_K + 2 * (QK_K / 16));
+
+/// Assemble a BlockQ8K from its scale and quantized values.
+fn block_q8k_from_parts(d: f32, qs: &[i8; QK_K]) -> BlockQ8K {
+    let mut block = BlockQ8K::zeros();
+    // BlockQ8K is #[repr(C)] { d: f32, qs: [i8; QK_K], bsums: [i16; QK_K / 16] } with
+    // crate-private fields; fill it through the same layout the .q8k files are written in.
+    unsafe {
+        let base = &mut block as *mut BlockQ8K as *mut u8;
+        std::ptr::write_unaligned(base as *mut f32, d);
+        std::ptr::copy_nonoverlapping(qs.as_ptr() as *const u8, base.add(4), QK_K);
+        let bsums = base.add(4 + QK_K) as *mut i16;
+        for (g, chunk) in qs.chunks_exact(16).enumerate() {
+            let sum: i32 = chunk.iter().map(|&q| q as i32).sum();
+            std::ptr::write_unaligned(bsums.add(g), sum as i16);
+        }
+    }
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_from_parts_dequantizes_to_scale_times_values() {
+        let d = 0.0375f32;
+        let qs: [i8; QK_K] = std::array::from_fn(|i| (i as i32 * 37 % 255 - 127) as i8);
+        let block = block_q8k_from_parts(d, &qs);
+        let mut values = [0f32; QK_K];
+        BlockQ8K::to_float(&[block], &mut values);
+        for (v, &q) in values.iter().zip(&qs) {
+            assert_eq!(*v, d * q as f32);
+        }
+    }
+}
@@ -1,5 +1,9 @@
 //! Q8K file format header definitions.
 
+use super::gguf::{ggml_dtype_from_id, ggml_type_id};
+use anyhow::{bail, Result};
+use candle_core::quantized::GgmlDType;
+
 #[repr(C)]
 #[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
 pub struct Q8KHeader {
@@ -11,6 +15,268 @@ pub struct Q8KHeader {
     pub dtype: u32,
 }
 
+/// Version 2 header: the v1 fields, then where the tensor came from, a checksum of the
+/// payload and room for later fields. The blocks start at `header_size`; an embedded
+/// permutation (`k` little-endian `u32`) follows them at `perm_offset`.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct Q8KHeaderV2 {
+    pub magic: u32,
+    pub version: u32,
+    pub out: u32,
+    pub k: u32,
+    pub blocks_per_row: u32,
+    pub dtype: u32,
+    /// Bytes before the first block
+    pub header_size: u32,
+    /// `FLAG_*` bits
+    pub flags: u32,
+    /// GGML type id of the source tensor, `ORIG_DTYPE_UNKNOWN` if it had none
+    pub orig_dtype: u32,
+    /// Rank of the source tensor; its dims are `orig_shape[..rank]`
+    pub rank: u32,
+    pub orig_shape: [u32; 4],
+    /// CRC32 of everything after the header
+    pub checksum: u32,
+    /// Entries of the embedded permutation; 0 without one
+    pub perm_len: u32,
+    /// File offset of the embedded permutation; 0 without one
+    pub perm_offset: u64,
+    /// Strategy name, UTF-8 padded with NULs; empty when the columns were not reordered
+    pub strategy: [u8; 32],
+    /// Strategy parameters as comma-separated `key=value` pairs, padded with NULs
+    pub strategy_params: [u8; 96],
+    /// Zero in version 2
+    pub reserved: [u8; 56],
+}
+
 pub const MAGIC_Q8K: u32 = 0x4B51_3838; // "KQ88" little-endian
-pub const VERSION: u32 = 1;
+/// Version written by this build; `VERSION_V1` files are still read.
+pub const VERSION: u32 = 2;
+pub const VERSION_V1: u32 = 1;
+pub const HEADER_V1_SIZE: usize = std::mem::size_of::<Q8KHeader>();
+pub const HEADER_V2_SIZE: usize = std::mem::size_of::<Q8KHeaderV2>();
+/// `Q8KHeaderV2::flags`: a permutation is embedded after the blocks
+pub const FLAG_EMBEDDED_PERM: u32 = 1;
+/// `Q8KHeaderV2::orig_dtype` of a source tensor without a ggml type
+pub const ORIG_DTYPE_UNKNOWN: u32 = u32::MAX;
+pub const DTYPE_Q2K: u32 = 0x12; // BlockQ2K format identifier
+pub const DTYPE_Q3K: u32 = 0x13; // BlockQ3K format identifier
+pub const DTYPE_Q4K: u32 = 0x14; // BlockQ4K format identifier
+pub const DTYPE_Q5K: u32 = 0x15; // BlockQ5K format identifier
+pub const DTYPE_Q6K: u32 = 0x16; // BlockQ6K format identifier
 pub const DTYPE_Q8K: u32 = 0x18; // BlockQ8K format identifier
+
+/// Block formats a `.q8k` file can hold, selected by the header `dtype`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuantFormat {
+    Q2K,
+    Q3K,
+    Q4K,
+    Q5K,
+    Q6K,
+    #[default]
+    Q8K,
+}
+
+impl QuantFormat {
+    pub const ALL: [QuantFormat; 6] = [
+        QuantFormat::Q2K,
+        QuantFormat::Q3K,
+        QuantFormat::Q4K,
+        QuantFormat::Q5K,
+        QuantFormat::Q6K,
+        QuantFormat::Q8K,
+    ];
+
+    pub fn dtype_id(self) -> u32 {
+        match self {
+            QuantFormat::Q2K => DTYPE_Q2K,
+            QuantFormat::Q3K => DTYPE_Q3K,
+            QuantFormat::Q4K => DTYPE_Q4K,
+            QuantFormat::Q5K => DTYPE_Q5K,
+            QuantFormat::Q6K => DTYPE_Q6K,
+            QuantFormat::Q8K => DTYPE_Q8K,
+        }
+    }
+
+    pub fn from_dtype_id(dtype: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.dtype_id() == dtype)
+    }
+
+    pub fn ggml_dtype(self) -> GgmlDType {
+        match self {
+            QuantFormat::Q2K => GgmlDType::Q2K,
+            QuantFormat::Q3K => GgmlDType::Q3K,
+            QuantFormat::Q4K => GgmlDType::Q4K,
+            QuantFormat::Q5K => GgmlDType::Q5K,
+            QuantFormat::Q6K => GgmlDType::Q6K,
+            QuantFormat::Q8K => GgmlDType::Q8K,
+        }
+    }
+
+    pub fn from_ggml_dtype(dtype: GgmlDType) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.ggml_dtype() == dtype)
+    }
+
+    /// Lowercase name as used on the command line, e.g. `q4k`.
+    pub fn name(self) -> &'static str {
+        match self {
+            QuantFormat::Q2K => "q2k",
+            QuantFormat::Q3K => "q3k",
+            QuantFormat::Q4K => "q4k",
+            QuantFormat::Q5K => "q5k",
+            QuantFormat::Q6K => "q6k",
+            QuantFormat::Q8K => "q8k",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|f| f.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Where a tensor came from and how its columns were ordered, recorded in a v2 header.
+#[derive(Debug, Clone, Default)]
+pub struct Q8KMetadata {
+    pub orig_dtype: Option<GgmlDType>,
+    /// Up to 4 dims; empty means the stored `[out, k]`
+    pub orig_shape: Vec<usize>,
+    pub strategy: Option<String>,
+    pub strategy_params: String,
+}
+
+impl Q8KHeaderV2 {
+    /// Parse the header at the start of `bytes`, of either version. A v1 header comes back
+    /// with `header_size` 24, no checksum and unknown origin.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 8 {
+            bail!("too small for a header");
+        }
+        let magic = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        if magic != MAGIC_Q8K {
+            bail!("bad magic {magic:#010x}");
+        }
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        match version {
+            VERSION_V1 => {
+                if bytes.len() < HEADER_V1_SIZE {
+                    bail!("too small for the {HEADER_V1_SIZE}-byte v1 header");
+                }
+                let v1: Q8KHeader = bytemuck::pod_read_unaligned(&bytes[..HEADER_V1_SIZE]);
+                Ok(Self::from_v1(&v1))
+            }
+            VERSION => {
+                if bytes.len() < HEADER_V2_SIZE {
+                    bail!("too small for the {HEADER_V2_SIZE}-byte v2 header");
+                }
+                let hdr: Self = bytemuck::pod_read_unaligned(&bytes[..HEADER_V2_SIZE]);
+                if (hdr.header_size as usize) < HEADER_V2_SIZE {
+                    bail!("header size {} below {HEADER_V2_SIZE}", hdr.header_size);
+                }
+                if hdr.rank as usize > hdr.orig_shape.len() {
+                    bail!("rank {} above {}", hdr.rank, hdr.orig_shape.len());
+                }
+                Ok(hdr)
+            }
+            v if v > VERSION => bail!(
+                "format version {v} is newer than this build, which reads versions \
+                 {VERSION_V1} to {VERSION}"
+            ),
+            v => bail!("unknown format version {v}"),
+        }
+    }
+
+    fn from_v1(v1: &Q8KHeader) -> Self {
+        Self {
+            magic: v1.magic,
+            version: v1.version,
+            out: v1.out,
+            k: v1.k,
+            blocks_per_row: v1.blocks_per_row,
+            dtype: v1.dtype,
+            header_size: HEADER_V1_SIZE as u32,
+            orig_dtype: ORIG_DTYPE_UNKNOWN,
+            ..bytemuck::Zeroable::zeroed()
+        }
+    }
+
+    /// A v2 header for `out x k` blocks of `format` with the given provenance.
+    pub fn new(format: QuantFormat, out: usize, k: usize, meta: &Q8KMetadata) -> Result<Self> {
+        if meta.orig_shape.len() > 4 {
+            bail!("source rank {} above 4", meta.orig_shape.len());
+        }
+        let mut orig_shape = [0u32; 4];
+        let shape = if meta.orig_shape.is_empty() {
+            &[out, k][..]
+        } else {
+            &meta.orig_shape
+        };
+        for (dst, &dim) in orig_shape.iter_mut().zip(shape) {
+            *dst = dim as u32;
+        }
+        Ok(Self {
+            magic: MAGIC_Q8K,
+            version: VERSION,
+            out: out as u32,
+            k: k as u32,
+            blocks_per_row: (k / format.ggml_dtype().block_size()) as u32,
+            dtype: format.dtype_id(),
+            header_size: HEADER_V2_SIZE as u32,
+            orig_dtype: meta.orig_dtype.map_or(ORIG_DTYPE_UNKNOWN, ggml_type_id),
+            rank: shape.len() as u32,
+            orig_shape,
+            strategy: padded(meta.strategy.as_deref().unwrap_or_default()),
+            strategy_params: padded(&meta.strategy_params),
+            ..bytemuck::Zeroable::zeroed()
+        })
+    }
+
+    pub fn data_offset(&self) -> usize {
+        self.header_size as usize
+    }
+
+    pub fn has_checksum(&self) -> bool {
+        self.version >= VERSION
+    }
+
+    pub fn has_embedded_perm(&self) -> bool {
+        self.flags & FLAG_EMBEDDED_PERM != 0
+    }
+
+    /// Source dims; empty for v1 files.
+    pub fn orig_shape(&self) -> &[u32] {
+        &self.orig_shape[..(self.rank as usize).min(self.orig_shape.len())]
+    }
+
+    /// Source ggml type; `None` for v1 files and sources without one.
+    pub fn orig_dtype(&self) -> Option<GgmlDType> {
+        ggml_dtype_from_id(self.orig_dtype)
+    }
+
+    pub fn strategy(&self) -> Option<&str> {
+        Some(unpadded(&self.strategy)).filter(|s| !s.is_empty())
+    }
+
+    pub fn strategy_params(&self) -> &str {
+        unpadded(&self.strategy_params)
+    }
+}
+
+/// `s` NUL-padded to `N` bytes, cut at a character boundary if longer.
+fn padded<const N: usize>(s: &str) -> [u8; N] {
+    let mut end = s.len().min(N);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut out = [0u8; N];
+    out[..end].copy_from_slice(&s.as_bytes()[..end]);
+    out
+}
+
+fn unpadded(bytes: &[u8]) -> &str {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    std::str::from_utf8(&bytes[..end]).unwrap_or_default()
+}
@@ -0,0 +1,651 @@
+//! Diagnostics for `.q8k` files, their embedded permutation and `.perm` / `.scale` sidecars.
+//!
+//! Unlike `load_quantized`, nothing here fails on a malformed file: every inconsistency is
+//! collected in `problems` and whatever can still be read is summarized. Block fields are
+//! read from the raw bytes, so a file whose header disagrees with its size is still shown.
+
+use super::header::{Q8KHeaderV2, QuantFormat, MAGIC_Q8K};
+use super::io::{load_scales, perm_from_le_bytes, MAGIC_PERM};
+use anyhow::{Context, Result};
+use candle_core::quantized::k_quants::{
+    BlockQ2K, BlockQ3K, BlockQ4K, BlockQ5K, BlockQ6K, BlockQ8K, QK_K,
+};
+use half::f16;
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+/// Min, max, mean and standard deviation of a set of values.
+#[derive(Debug, Clone, Serialize)]
+pub struct ValueStats {
+    pub count: usize,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub std: f32,
+}
+
+impl ValueStats {
+    /// `None` when there are no finite values.
+    pub fn of(values: impl Iterator<Item = f32>) -> Option<Self> {
+        let (mut count, mut sum, mut sum_sq) = (0usize, 0f64, 0f64);
+        let (mut min, mut max) = (f32::INFINITY, f32::NEG_INFINITY);
+        for v in values.filter(|v| v.is_finite()) {
+            count += 1;
+            sum += v as f64;
+            sum_sq += v as f64 * v as f64;
+            min = min.min(v);
+            max = max.max(v);
+        }
+        if count == 0 {
+            return None;
+        }
+        let mean = sum / count as f64;
+        Some(Self {
+            count,
+            min,
+            max,
+            mean: mean as f32,
+            std: (sum_sq / count as f64 - mean * mean).max(0.0).sqrt() as f32,
+        })
+    }
+}
+
+impl fmt::Display for ValueStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "min {:.4e}, max {:.4e}, mean {:.4e}, std {:.4e} (n = {})",
+            self.min, self.max, self.mean, self.std, self.count
+        )
+    }
+}
+
+/// Block sums of a Q8K file checked against the `qs` they summarize.
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockSums {
+    pub min: i16,
+    pub max: i16,
+    /// Groups of 16 whose stored sum differs from the sum of their `qs`
+    pub mismatched: usize,
+}
+
+/// Everything `inspect_quantized` found out about a `.q8k` file.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuantizedFileInspection {
+    pub path: PathBuf,
+    pub file_size: u64,
+    pub magic: u32,
+    pub version: u32,
+    pub rows: u32,
+    pub k: u32,
+    pub blocks_per_row: u32,
+    pub dtype: u32,
+    /// Format named by `dtype`, if known
+    pub format: Option<String>,
+    /// Bytes before the first block: 24 in version 1, 256 in version 2
+    pub header_size: u32,
+    /// GGML type of the source tensor (version 2)
+    pub orig_dtype: Option<String>,
+    /// Shape of the source tensor; empty in version 1
+    pub orig_shape: Vec<u32>,
+    /// Strategy that ordered the columns (version 2)
+    pub strategy: Option<String>,
+    pub strategy_params: Option<String>,
+    /// Stored CRC32 of the payload (version 2)
+    pub checksum: Option<u32>,
+    /// Whether the payload matches `checksum`
+    pub checksum_ok: Option<bool>,
+    /// Header plus `rows * blocks_per_row` blocks and any embedded permutation
+    pub expected_size: Option<u64>,
+    /// Super-block scale `d` over all blocks read
+    pub d: Option<ValueStats>,
+    /// Largest `|d|` of each row, over rows
+    pub row_max_d: Option<ValueStats>,
+    pub zero_d_blocks: usize,
+    pub non_finite_d_blocks: usize,
+    /// Count of each quantized value -128..=127 (Q8K only)
+    pub qs_histogram: Option<Vec<u64>>,
+    /// Q8K only
+    pub bsums: Option<BlockSums>,
+    /// The `.perm` sidecar; its problems are not repeated in `problems`
+    pub perm: Option<PermInspection>,
+    /// The permutation embedded after the blocks, with `path` set to the file itself
+    pub embedded_perm: Option<PermInspection>,
+    /// Per-input-channel scales of the `.scale` sidecar
+    pub scales: Option<ValueStats>,
+    pub problems: Vec<String>,
+}
+
+impl QuantizedFileInspection {
+    /// No problems in the file or its permutations.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+            && [&self.perm, &self.embedded_perm]
+                .into_iter()
+                .flatten()
+                .all(|p| p.problems.is_empty())
+    }
+}
+
+/// Everything `inspect_perm` found out about a `.perm` file.
+#[derive(Debug, Clone, Serialize)]
+pub struct PermInspection {
+    pub path: PathBuf,
+    /// Read from the `.q8k` file at `path` rather than a `.perm` sidecar
+    pub embedded: bool,
+    pub file_size: u64,
+    pub k: u32,
+    /// Entries `>= k`
+    pub out_of_range: usize,
+    /// Entries that repeat an earlier one
+    pub duplicates: usize,
+    /// Columns in `0..k` that never appear
+    pub missing: usize,
+    /// Positions holding their own column
+    pub fixed_points: usize,
+    /// `|perm[i] - i|`
+    pub displacement: Option<ValueStats>,
+    /// Columns moved into another block of `QK_K`
+    pub cross_block: usize,
+    pub problems: Vec<String>,
+}
+
+impl PermInspection {
+    pub fn is_bijection(&self) -> bool {
+        self.out_of_range == 0 && self.duplicates == 0 && self.missing == 0
+    }
+}
+
+/// Block size and byte offset of the super-block scale `d` (f32 for Q8K, f16 otherwise).
+fn block_layout(format: QuantFormat) -> (usize, usize) {
+    match format {
+        QuantFormat::Q2K => (mem::size_of::<BlockQ2K>(), QK_K / 16 + QK_K / 4),
+        QuantFormat::Q3K => (mem::size_of::<BlockQ3K>(), QK_K / 8 + QK_K / 4 + 12),
+        QuantFormat::Q4K => (mem::size_of::<BlockQ4K>(), 0),
+        QuantFormat::Q5K => (mem::size_of::<BlockQ5K>(), 0),
+        QuantFormat::Q6K => (mem::size_of::<BlockQ6K>(), QK_K / 2 + QK_K / 4 + QK_K / 16),
+        QuantFormat::Q8K => (mem::size_of::<BlockQ8K>(), 0),
+    }
+}
+
+/// Inspect a `.q8k` file of either header version and the `.perm` and `.scale` sidecars
+/// next to it.
+pub fn inspect_quantized(path: &Path) -> Result<QuantizedFileInspection> {
+    let data = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let mut report = QuantizedFileInspection {
+        path: path.to_path_buf(),
+        file_size: data.len() as u64,
+        magic: 0,
+        version: 0,
+        rows: 0,
+        k: 0,
+        blocks_per_row: 0,
+        dtype: 0,
+        format: None,
+        header_size: 0,
+        orig_dtype: None,
+        orig_shape: Vec::new(),
+        strategy: None,
+        strategy_params: None,
+        checksum: None,
+        checksum_ok: None,
+        expected_size: None,
+        d: None,
+        row_max_d: None,
+        zero_d_blocks: 0,
+        non_finite_d_blocks: 0,
+        qs_histogram: None,
+        bsums: None,
+        perm: None,
+        embedded_perm: None,
+        scales: None,
+        problems: Vec::new(),
+    };
+    if data.len() < 8 {
+        report.problems.push(format!(
+            "file is {} bytes, shorter than the header",
+            data.len()
+        ));
+        return Ok(report);
+    }
+    report.magic = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    report.version = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    if report.magic != MAGIC_Q8K {
+        report.problems.push(format!(
+            "bad magic {:#010x}, expected {MAGIC_Q8K:#010x}",
+            report.magic
+        ));
+        return Ok(report);
+    }
+    let hdr = match Q8KHeaderV2::parse(&data) {
+        Ok(hdr) => hdr,
+        Err(e) => {
+            report.problems.push(e.to_string());
+            return Ok(report);
+        }
+    };
+    let header_size = hdr.data_offset();
+    report.rows = hdr.out;
+    report.k = hdr.k;
+    report.blocks_per_row = hdr.blocks_per_row;
+    report.dtype = hdr.dtype;
+    report.header_size = hdr.header_size;
+    report.orig_dtype = hdr.orig_dtype().map(|d| format!("{d:?}"));
+    report.orig_shape = hdr.orig_shape().to_vec();
+    report.strategy = hdr.strategy().map(str::to_string);
+    report.strategy_params = Some(hdr.strategy_params().to_string()).filter(|p| !p.is_empty());
+    if hdr.has_checksum() {
+        let actual = crc32fast::hash(data.get(header_size..).unwrap_or_default());
+        report.checksum = Some(hdr.checksum);
+        report.checksum_ok = Some(actual == hdr.checksum);
+        if actual != hdr.checksum {
+            report.problems.push(format!(
+                "checksum {:#010x} does not match the payload ({actual:#010x})",
+                hdr.checksum
+            ));
+        }
+    }
+    let Some(format) = QuantFormat::from_dtype_id(hdr.dtype) else {
+        report
+            .problems
+            .push(format!("unknown dtype {:#x}", hdr.dtype));
+        return Ok(report);
+    };
+    report.format = Some(format.name().to_string());
+    let (block_size, d_offset) = block_layout(format);
+    let (rows, k, blocks_per_row) = (
+        hdr.out as usize,
+        hdr.k as usize,
+        hdr.blocks_per_row as usize,
+    );
+    if k % QK_K != 0 || k / QK_K != blocks_per_row {
+        report.problems.push(format!(
+            "k = {k} does not match {blocks_per_row} blocks of {QK_K} per row"
+        ));
+    }
+    let overflow = || format!("{rows} rows of {blocks_per_row} blocks overflow the file size");
+    let Some(blocks_end) = rows
+        .checked_mul(blocks_per_row)
+        .and_then(|n| n.checked_mul(block_size))
+        .and_then(|n| n.checked_add(header_size))
+    else {
+        report.problems.push(overflow());
+        return Ok(report);
+    };
+    let mut expected = blocks_end;
+    if hdr.has_embedded_perm() {
+        let Some(end) = expected.checked_add(4 * hdr.perm_len as usize) else {
+            report.problems.push(overflow());
+            return Ok(report);
+        };
+        expected = end;
+        if hdr.perm_offset != blocks_end as u64 {
+            report.problems.push(format!(
+                "embedded permutation at {}, blocks end at {blocks_end}",
+                hdr.perm_offset
+            ));
+        }
+    }
+    report.expected_size = Some(expected as u64);
+    if data.len() != expected {
+        report.problems.push(format!(
+            "file is {} bytes, header implies {expected}",
+            data.len()
+        ));
+    }
+
+    // Read as many whole rows as the file holds
+    let blocks = &data[header_size.min(data.len())..blocks_end.min(data.len())];
+    let whole_rows = if blocks_per_row == 0 {
+        0
+    } else {
+        rows.min(blocks.len() / (block_size * blocks_per_row))
+    };
+    let block_d = |b: usize| {
+        let at = b * block_size + d_offset;
+        if format == QuantFormat::Q8K {
+            f32::from_le_bytes(blocks[at..at + 4].try_into().unwrap())
+        } else {
+            f16::from_le_bytes([blocks[at], blocks[at + 1]]).to_f32()
+        }
+    };
+    let d: Vec<f32> = (0..whole_rows * blocks_per_row).map(block_d).collect();
+    report.zero_d_blocks = d.iter().filter(|&&d| d == 0.0).count();
+    report.non_finite_d_blocks = d.iter().filter(|d| !d.is_finite()).count();
+    if report.non_finite_d_blocks > 0 {
+        report.problems.push(format!(
+            "{} block(s) with a non-finite scale",
+            report.non_finite_d_blocks
+        ));
+    }
+    report.d = ValueStats::of(d.iter().copied());
+    report.row_max_d = ValueStats::of(
+        d.chunks(blocks_per_row.max(1))
+            .map(|row| row.iter().fold(0f32, |m, d| m.max(d.abs()))),
+    );
+
+    if format == QuantFormat::Q8K {
+        let mut histogram = vec![0u64; 256];
+        let mut sums = BlockSums {
+            min: i16::MAX,
+            max: i16::MIN,
+            mismatched: 0,
+        };
+        for block in blocks.chunks_exact(block_size).take(d.len()) {
+            let qs = &block[4..4 + QK_K];
+            for &q in qs {
+                histogram[(q as i8 as i32 + 128) as usize] += 1;
+            }
+            let bsums = &block[4 + QK_K..];
+            for (g, group) in qs.chunks_exact(16).enumerate() {
+                let stored = i16::from_le_bytes([bsums[2 * g], bsums[2 * g + 1]]);
+                let actual: i32 = group.iter().map(|&q| q as i8 as i32).sum();
+                sums.min = sums.min.min(stored);
+                sums.max = sums.max.max(stored);
+                if stored as i32 != actual {
+                    sums.mismatched += 1;
+                }
+            }
+        }
+        if sums.mismatched > 0 {
+            report.problems.push(format!(
+                "{} block sum(s) disagree with their qs",
+                sums.mismatched
+            ));
+        }
+        report.qs_histogram = Some(histogram);
+        report.bsums = (!d.is_empty()).then_some(sums);
+    }
+
+    let embedded = if hdr.has_embedded_perm() {
+        let start = (hdr.perm_offset as usize).min(data.len());
+        let bytes = &data[start..(start + 4 * hdr.perm_len as usize).min(data.len())];
+        let perm = perm_from_le_bytes(bytes);
+        let mut inspection = check_perm(path, bytes.len() as u64, hdr.perm_len, &perm);
+        inspection.embedded = true;
+        if perm.len() != hdr.perm_len as usize {
+            inspection.problems.push(format!(
+                "{} of {} entries present",
+                perm.len(),
+                hdr.perm_len
+            ));
+        }
+        report.embedded_perm = Some(inspection);
+        Some(perm)
+    } else {
+        None
+    };
+    let mut perm_path = path.to_path_buf();
+    perm_path.set_extension("perm");
+    if perm_path.exists() {
+        let perm = inspect_perm(&perm_path)?;
+        if perm.k != hdr.k {
+            report.problems.push(format!(
+                "permutation has {} entries for k = {}",
+                perm.k, hdr.k
+            ));
+        }
+        if let Some(ref embedded) = embedded {
+            let sidecar = fs::read(&perm_path)?;
+            if sidecar.get(8..).map(perm_from_le_bytes).as_ref() != Some(embedded) {
+                report
+                    .problems
+                    .push(".perm sidecar differs from the embedded permutation".to_string());
+            }
+        }
+        report.perm = Some(perm);
+    }
+    if let Some(ref perm) = report.embedded_perm {
+        if perm.k != hdr.k {
+            report.problems.push(format!(
+                "embedded permutation has {} entries for k = {}",
+                perm.k, hdr.k
+            ));
+        }
+    }
+    match load_scales(path) {
+        Ok(Some(scales)) => {
+            if scales.len() != k {
+                report.problems.push(format!(
+                    "scale sidecar has {} entries for k = {k}",
+                    scales.len()
+                ));
+            }
+            let bad = scales
+                .iter()
+                .filter(|s| !s.is_finite() || **s <= 0.0)
+                .count();
+            if bad > 0 {
+                report
+                    .problems
+                    .push(format!("{bad} scale(s) not finite and positive"));
+            }
+            report.scales = ValueStats::of(scales.into_iter());
+        }
+        Ok(None) => {}
+        Err(e) => report.problems.push(format!("scale: {e}")),
+    }
+    Ok(report)
+}
+
+/// Inspect a `.perm` file: is it a bijection on `0..k`, and how far does it move columns.
+pub fn inspect_perm(path: &Path) -> Result<PermInspection> {
+    let data = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let file_size = data.len() as u64;
+    if data.len() < 8 {
+        let mut report = check_perm(path, file_size, 0, &[]);
+        report.problems.push(format!(
+            "file is {} bytes, shorter than the header",
+            data.len()
+        ));
+        return Ok(report);
+    }
+    let magic = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    if magic != MAGIC_PERM {
+        let mut report = check_perm(path, file_size, 0, &[]);
+        report.problems.push(format!(
+            "bad magic {magic:#010x}, expected {MAGIC_PERM:#010x}"
+        ));
+        return Ok(report);
+    }
+    let k = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    let held = (data.len() - 8) / 4;
+    let perm = perm_from_le_bytes(&data[8..8 + 4 * held.min(k as usize)]);
+    let mut report = check_perm(path, file_size, k, &perm);
+    let expected = 8 + 4 * k as u64;
+    if held < k as usize {
+        report.problems.insert(
+            0,
+            format!("header claims {k} entries, the file holds {held}"),
+        );
+    } else if file_size != expected {
+        report.problems.insert(
+            0,
+            format!("file is {} bytes, header implies {expected}", data.len()),
+        );
+    }
+    Ok(report)
+}
+
+/// Bijection and displacement statistics of `perm`, which should cover `0..k`. `k` comes from
+/// a file header, so the check covers at most the `perm.len()` entries actually read; callers
+/// report a shortfall.
+fn check_perm(path: &Path, file_size: u64, k: u32, perm: &[usize]) -> PermInspection {
+    let mut report = PermInspection {
+        path: path.to_path_buf(),
+        embedded: false,
+        file_size,
+        k,
+        out_of_range: 0,
+        duplicates: 0,
+        missing: 0,
+        fixed_points: 0,
+        displacement: None,
+        cross_block: 0,
+        problems: Vec::new(),
+    };
+    let k = (k as usize).min(perm.len());
+    let mut seen = vec![false; k];
+    for (i, &p) in perm.iter().enumerate() {
+        if p >= k {
+            report.out_of_range += 1;
+            continue;
+        }
+        if seen[p] {
+            report.duplicates += 1;
+        }
+        seen[p] = true;
+        if p == i {
+            report.fixed_points += 1;
+        }
+        if p / QK_K != i / QK_K {
+            report.cross_block += 1;
+        }
+    }
+    report.missing = seen.iter().filter(|&&s| !s).count();
+    report.displacement = ValueStats::of(
+        perm.iter()
+            .enumerate()
+            .filter(|&(_, &p)| p < k)
+            .map(|(i, &p)| i.abs_diff(p) as f32),
+    );
+    if !report.is_bijection() {
+        report.problems.push(format!(
+            "not a bijection on 0..{k}: {} out of range, {} duplicate(s), {} missing",
+            report.out_of_range, report.duplicates, report.missing
+        ));
+    }
+    report
+}
+
+impl fmt::Display for QuantizedFileInspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.path.display())?;
+        writeln!(f, "  magic          : {:#010x}", self.magic)?;
+        writeln!(f, "  version        : {}", self.version)?;
+        if self.header_size > 0 {
+            writeln!(f, "  header size    : {} bytes", self.header_size)?;
+        }
+        writeln!(f, "  shape          : {} x {}", self.rows, self.k)?;
+        writeln!(f, "  blocks per row : {}", self.blocks_per_row)?;
+        writeln!(
+            f,
+            "  dtype          : {:#x} ({})",
+            self.dtype,
+            self.format.as_deref().unwrap_or("unknown")
+        )?;
+        if self.orig_dtype.is_some() || !self.orig_shape.is_empty() {
+            writeln!(
+                f,
+                "  source         : {} {:?}",
+                self.orig_dtype.as_deref().unwrap_or("unknown type"),
+                self.orig_shape
+            )?;
+        }
+        if let Some(ref strategy) = self.strategy {
+            match self.strategy_params {
+                Some(ref params) => writeln!(f, "  strategy       : {strategy} ({params})")?,
+                None => writeln!(f, "  strategy       : {strategy}")?,
+            }
+        }
+        if let (Some(checksum), Some(ok)) = (self.checksum, self.checksum_ok) {
+            writeln!(
+                f,
+                "  checksum       : {checksum:#010x} ({})",
+                if ok { "ok" } else { "MISMATCH" }
+            )?;
+        }
+        match self.expected_size {
+            Some(expected) => writeln!(
+                f,
+                "  file size      : {} bytes (expected {expected})",
+                self.file_size
+            )?,
+            None => writeln!(f, "  file size      : {} bytes", self.file_size)?,
+        }
+        if let Some(ref d) = self.d {
+            writeln!(f, "  block d        : {d}")?;
+            writeln!(
+                f,
+                "                   {} zero, {} non-finite",
+                self.zero_d_blocks, self.non_finite_d_blocks
+            )?;
+        }
+        if let Some(ref d) = self.row_max_d {
+            writeln!(f, "  row max |d|    : {d}")?;
+        }
+        if let Some(ref histogram) = self.qs_histogram {
+            let total: u64 = histogram.iter().sum();
+            writeln!(f, "  qs histogram   :")?;
+            for (bucket, counts) in histogram.chunks(16).enumerate() {
+                let count: u64 = counts.iter().sum();
+                let share = count as f64 / total.max(1) as f64;
+                let lo = bucket as i32 * 16 - 128;
+                writeln!(
+                    f,
+                    "    [{lo:>4}, {:>4}] {:>6.2}% {}",
+                    lo + 15,
+                    share * 100.0,
+                    "#".repeat((share * 50.0).round() as usize)
+                )?;
+            }
+            writeln!(f, "    -128: {}, 127: {}", histogram[0], histogram[255])?;
+        }
+        if let Some(ref sums) = self.bsums {
+            writeln!(
+                f,
+                "  bsums          : min {}, max {}, {} mismatched",
+                sums.min, sums.max, sums.mismatched
+            )?;
+        }
+        if let Some(ref scales) = self.scales {
+            writeln!(f, "  scales         : {scales}")?;
+        }
+        write_problems(f, &self.problems)?;
+        if let Some(ref perm) = self.embedded_perm {
+            write!(f, "{perm}")?;
+        }
+        match self.perm {
+            Some(ref perm) => write!(f, "{perm}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for PermInspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.embedded {
+            writeln!(f, "{} (embedded permutation)", self.path.display())?;
+            writeln!(f, "  k              : {}", self.k)?;
+            writeln!(f, "  size           : {} bytes", self.file_size)?;
+        } else {
+            writeln!(f, "{}", self.path.display())?;
+            writeln!(f, "  k              : {}", self.k)?;
+            writeln!(f, "  file size      : {} bytes", self.file_size)?;
+        }
+        writeln!(
+            f,
+            "  bijection      : {}",
+            if self.is_bijection() { "yes" } else { "no" }
+        )?;
+        writeln!(f, "  fixed points   : {}", self.fixed_points)?;
+        writeln!(f, "  cross-block    : {}", self.cross_block)?;
+        if let Some(ref d) = self.displacement {
+            writeln!(f, "  displacement   : {d}")?;
+        }
+        write_problems(f, &self.problems)
+    }
+}
+
+fn write_problems(f: &mut fmt::Formatter<'_>, problems: &[String]) -> fmt::Result {
+    if problems.is_empty() {
+        return writeln!(f, "  OK");
+    }
+    for problem in problems {
+        writeln!(f, "  PROBLEM: {problem}")?;
+    }
+    Ok(())
+}
@@ -1,41 +1,80 @@
 //! File I/O operations for Q8K format.
 
-use super::header::{Q8KHeader, DTYPE_Q8K, MAGIC_Q8K, VERSION};
-use anyhow::{bail, Result};
-use candle_core::quantized::k_quants::{BlockQ8K, QK_K};
+use super::header::{Q8KHeaderV2, Q8KMetadata, QuantFormat, FLAG_EMBEDDED_PERM, HEADER_V2_SIZE};
+use anyhow::{bail, Context, Result};
+use candle_core::quantized::k_quants::BlockQ8K;
 use candle_core::quantized::GgmlType;
+use memmap2::Mmap;
 use std::fs;
-use std::io::{BufWriter, Write};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+const MAGIC_SCALE: u32 = 0x4C41_4353; // "SCAL"
+pub(crate) const MAGIC_PERM: u32 = 0x4D52_4550; // "PERM"
 
 pub fn write_q8k(path: &Path, rows: usize, k: usize, blocks: &[BlockQ8K]) -> Result<()> {
-    let header = Q8KHeader {
-        magic: MAGIC_Q8K,
-        version: VERSION,
-        out: rows as u32,
-        k: k as u32,
-        blocks_per_row: (k / QK_K) as u32,
-        dtype: DTYPE_Q8K,
+    write_quantized(path, rows, k, blocks)
+}
+
+/// Write blocks of any supported k-quant type, tagging the header with its dtype id.
+pub fn write_quantized<T: GgmlType>(
+    path: &Path,
+    rows: usize,
+    k: usize,
+    blocks: &[T],
+) -> Result<()> {
+    write_quantized_with(path, rows, k, blocks, &Q8KMetadata::default(), None)
+}
+
+/// Like `write_quantized`, recording `meta` in the header and embedding `perm` (the source
+/// column of each stored column) after the blocks.
+pub fn write_quantized_with<T: GgmlType>(
+    path: &Path,
+    rows: usize,
+    k: usize,
+    blocks: &[T],
+    meta: &Q8KMetadata,
+    perm: Option<&[usize]>,
+) -> Result<()> {
+    let format = QuantFormat::from_ggml_dtype(T::DTYPE)
+        .with_context(|| format!("unsupported block type {:?}", T::DTYPE))?;
+    let mut header = Q8KHeaderV2::new(format, rows, k, meta)?;
+    let raw = unsafe {
+        std::slice::from_raw_parts(blocks.as_ptr() as *const u8, mem::size_of_val(blocks))
     };
+    let perm_bytes: Vec<u8> = perm
+        .unwrap_or_default()
+        .iter()
+        .flat_map(|&p| (p as u32).to_le_bytes())
+        .collect();
+    if let Some(perm) = perm {
+        if perm.len() != k {
+            bail!("{} permutation entries for k = {k}", perm.len());
+        }
+        header.flags |= FLAG_EMBEDDED_PERM;
+        header.perm_len = k as u32;
+        header.perm_offset = (header.data_offset() + raw.len()) as u64;
+    }
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(raw);
+    crc.update(&perm_bytes);
+    header.checksum = crc.finalize();
+
     let mut w = BufWriter::new(fs::File::create(path)?);
     w.write_all(bytemuck::bytes_of(&header))?;
-    let raw = unsafe {
-        std::slice::from_raw_parts(
-            blocks.as_ptr() as *const u8,
-            blocks.len() * mem::size_of::<BlockQ8K>(),
-        )
-    };
     w.write_all(raw)?;
+    w.write_all(&perm_bytes)?;
     w.flush()?;
     Ok(())
 }
 
+/// Write the `.perm` sidecar of a version 1 file; version 2 output embeds the permutation.
 pub fn write_perm(path_q8k: &Path, perm: &[usize]) -> Result<()> {
     let mut p = path_q8k.to_path_buf();
     p.set_extension("perm");
     let mut w = BufWriter::new(fs::File::create(&p)?);
-    const MAGIC_PERM: u32 = 0x4D52_4550; // "PERM"
     w.write_all(&MAGIC_PERM.to_le_bytes())?;
     w.write_all(&(perm.len() as u32).to_le_bytes())?;
     for &u in perm {
@@ -45,7 +84,25 @@ pub fn write_perm(path_q8k: &Path, perm: &[usize]) -> Result<()> {
     Ok(())
 }
 
+/// The column permutation of a `.q8k` file, from its `.perm` sidecar or embedded in its
+/// header. A file carrying both must carry the same permutation twice.
 pub fn load_perm(path_q8k: &Path) -> Result<Option<Vec<usize>>> {
+    let sidecar = load_perm_sidecar(path_q8k)?;
+    let embedded = if path_q8k.exists() {
+        read_embedded_perm(path_q8k, &read_q8k_header(path_q8k)?)?
+    } else {
+        None
+    };
+    match (sidecar, embedded) {
+        (Some(a), Some(b)) if a != b => bail!(
+            "{}: .perm sidecar differs from the embedded permutation",
+            path_q8k.display()
+        ),
+        (sidecar, embedded) => Ok(sidecar.or(embedded)),
+    }
+}
+
+fn load_perm_sidecar(path_q8k: &Path) -> Result<Option<Vec<usize>>> {
     let mut p = path_q8k.to_path_buf();
     p.set_extension("perm");
     if !p.exists() {
@@ -56,7 +113,6 @@ pub fn load_perm(path_q8k: &Path) -> Result<Option<Vec<usize>>> {
         bail!("perm file too small: {}", p.display());
     }
     let magic = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
-    const MAGIC_PERM: u32 = 0x4D52_4550;
     if magic != MAGIC_PERM {
         bail!("bad perm magic in {}", p.display());
     }
@@ -79,33 +135,235 @@ pub fn load_perm(path_q8k: &Path) -> Result<Option<Vec<usize>>> {
     Ok(Some(perm))
 }
 
-pub fn load_q8k_tensor(path: &Path) -> Result<(Vec<BlockQ8K>, usize, usize, Option<Vec<usize>>)> {
-    let data = fs::read(path)?;
-    if data.len() < mem::size_of::<Q8KHeader>() {
-        bail!("file too small: {}", path.display());
+/// Quantized blocks, rows, inner dimension and optional column permutation.
+pub type QuantizedTensor<T> = (Vec<T>, usize, usize, Option<Vec<usize>>);
+pub type Q8KTensor = QuantizedTensor<BlockQ8K>;
+
+/// Write per-input-channel scales (original column order) next to a `.q8k` file.
+///
+/// The stored weights had each column multiplied by its scale, so activations must be
+/// divided by the same scale before the matmul.
+pub fn write_scales(path_q8k: &Path, scales: &[f32]) -> Result<()> {
+    let mut p = path_q8k.to_path_buf();
+    p.set_extension("scale");
+    let mut w = BufWriter::new(fs::File::create(&p)?);
+    w.write_all(&MAGIC_SCALE.to_le_bytes())?;
+    w.write_all(&(scales.len() as u32).to_le_bytes())?;
+    for &s in scales {
+        w.write_all(&s.to_le_bytes())?;
     }
+    w.flush()?;
+    Ok(())
+}
 
-    let hdr = *bytemuck::from_bytes::<Q8KHeader>(&data[..mem::size_of::<Q8KHeader>()]);
-    if hdr.magic != MAGIC_Q8K {
-        bail!("bad magic in {}", path.display());
+pub fn load_scales(path_q8k: &Path) -> Result<Option<Vec<f32>>> {
+    let mut p = path_q8k.to_path_buf();
+    p.set_extension("scale");
+    if !p.exists() {
+        return Ok(None);
+    }
+    let bytes = fs::read(&p)?;
+    if bytes.len() < 8 {
+        bail!("scale file too small: {}", p.display());
     }
-    if hdr.dtype != DTYPE_Q8K {
-        bail!("unexpected dtype in {}", path.display());
+    let magic = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+    if magic != MAGIC_SCALE {
+        bail!("bad scale magic in {}", p.display());
     }
+    let k = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+    let expect = 8 + 4 * k;
+    if bytes.len() != expect {
+        bail!(
+            "scale size mismatch {} (got {}, expect {})",
+            p.display(),
+            bytes.len(),
+            expect
+        );
+    }
+    Ok(Some(
+        bytes[8..]
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect(),
+    ))
+}
+
+pub fn load_q8k_tensor(path: &Path) -> Result<Q8KTensor> {
+    load_quantized(path)
+}
+
+/// Read only the header, e.g. to find out which block type a file holds. Version 1 headers
+/// are returned in the v2 layout; see `Q8KHeaderV2::parse`.
+pub fn read_q8k_header(path: &Path) -> Result<Q8KHeaderV2> {
+    let mut buf = Vec::with_capacity(HEADER_V2_SIZE);
+    fs::File::open(path)
+        .with_context(|| format!("opening {}", path.display()))?
+        .take(HEADER_V2_SIZE as u64)
+        .read_to_end(&mut buf)?;
+    Q8KHeaderV2::parse(&buf).with_context(|| format!("reading header of {}", path.display()))
+}
+
+/// The permutation embedded in `path` after its blocks, if the header flags one.
+fn read_embedded_perm(path: &Path, hdr: &Q8KHeaderV2) -> Result<Option<Vec<usize>>> {
+    if !hdr.has_embedded_perm() {
+        return Ok(None);
+    }
+    let mut bytes = vec![0u8; 4 * hdr.perm_len as usize];
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(hdr.perm_offset))?;
+    file.read_exact(&mut bytes)
+        .with_context(|| format!("embedded permutation of {} is truncated", path.display()))?;
+    Ok(Some(perm_from_le_bytes(&bytes)))
+}
+
+/// Little-endian `u32` entries as column indices.
+pub(crate) fn perm_from_le_bytes(bytes: &[u8]) -> Vec<usize> {
+    bytes
+        .chunks_exact(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]) as usize)
+        .collect()
+}
+
+/// Load a tensor whose blocks are of type `T`; fails if the header holds another dtype or,
+/// for version 2 files, if the payload does not match its checksum.
+pub fn load_quantized<T: GgmlType>(path: &Path) -> Result<QuantizedTensor<T>> {
+    let mapped = MappedQuantized::<T>::open(path)?;
+    mapped.verify_checksum()?;
+    let perm = load_perm(path)?;
+    Ok((mapped.blocks().to_vec(), mapped.rows(), mapped.k(), perm))
+}
 
-    let total_blocks = (hdr.out as usize) * (hdr.blocks_per_row as usize);
-    let expected = mem::size_of::<Q8KHeader>() + total_blocks * mem::size_of::<BlockQ8K>();
-    if data.len() != expected {
-        bail!("size mismatch in {}", path.display());
+/// A `.q8k` file mapped into memory, its blocks borrowed from the map without a copy.
+pub struct MappedQuantized<T> {
+    map: Mmap,
+    header: Q8KHeaderV2,
+    path: PathBuf,
+    _blocks: PhantomData<T>,
+}
+
+pub type MappedQ8K = MappedQuantized<BlockQ8K>;
+
+impl<T: GgmlType> MappedQuantized<T> {
+    /// Map `path` and check its header, dtype, size and block alignment. The checksum is not
+    /// verified here, as that reads every page; see `verify_checksum`. The file must not be
+    /// modified while it is mapped.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        // SAFETY: read-only map; the caller keeps the file unchanged while it is in use.
+        let map =
+            unsafe { Mmap::map(&file) }.with_context(|| format!("mapping {}", path.display()))?;
+        let header = Q8KHeaderV2::parse(&map)
+            .with_context(|| format!("reading header of {}", path.display()))?;
+        match QuantFormat::from_dtype_id(header.dtype) {
+            Some(format) if format.ggml_dtype() == T::DTYPE => {}
+            Some(format) => bail!(
+                "unexpected dtype in {}: file holds {}, requested {:?}",
+                path.display(),
+                format.name(),
+                T::DTYPE
+            ),
+            None => bail!("unknown dtype {:#x} in {}", header.dtype, path.display()),
+        }
+        if header.blocks_per_row as usize * T::BLCK_SIZE != header.k as usize {
+            bail!(
+                "{}: {} blocks per row do not hold k = {}",
+                path.display(),
+                header.blocks_per_row,
+                header.k
+            );
+        }
+
+        let total_blocks = (header.out as usize) * (header.blocks_per_row as usize);
+        let blocks_end = header.data_offset() + total_blocks * mem::size_of::<T>();
+        let mut expected = blocks_end;
+        if header.has_embedded_perm() {
+            if header.perm_len != header.k || header.perm_offset != blocks_end as u64 {
+                bail!(
+                    "{}: embedded permutation of {} entries at {} does not follow the blocks",
+                    path.display(),
+                    header.perm_len,
+                    header.perm_offset
+                );
+            }
+            expected += 4 * header.perm_len as usize;
+        }
+        if map.len() != expected {
+            bail!("size mismatch in {}", path.display());
+        }
+        let data = map[header.data_offset()..].as_ptr();
+        if !(data as usize).is_multiple_of(mem::align_of::<T>()) {
+            bail!(
+                "{}: block data at {data:p} is not {}-byte aligned",
+                path.display(),
+                mem::align_of::<T>()
+            );
+        }
+        Ok(Self {
+            map,
+            header,
+            path: path.to_path_buf(),
+            _blocks: PhantomData,
+        })
+    }
+
+    pub fn blocks(&self) -> &[T] {
+        let data = self.bytes();
+        // SAFETY: `open` checked that the data is aligned for `T` and holds exactly
+        // `out * blocks_per_row` blocks; k-quant blocks are plain integers and floats, valid
+        // for any bit pattern.
+        unsafe {
+            std::slice::from_raw_parts(data.as_ptr() as *const T, data.len() / mem::size_of::<T>())
+        }
+    }
+
+    /// The block data as raw bytes.
+    pub fn bytes(&self) -> &[u8] {
+        let total_blocks = self.rows() * self.header.blocks_per_row as usize;
+        let start = self.header.data_offset();
+        &self.map[start..start + total_blocks * mem::size_of::<T>()]
     }
 
-    let mut blocks = vec![BlockQ8K::zeros(); total_blocks];
-    let raw = &data[mem::size_of::<Q8KHeader>()..];
-    unsafe {
-        std::ptr::copy_nonoverlapping(raw.as_ptr(), blocks.as_mut_ptr() as *mut u8, raw.len());
+    /// The header, in the v2 layout whatever the file version.
+    pub fn header(&self) -> &Q8KHeaderV2 {
+        &self.header
     }
 
-    let perm = load_perm(path).ok().flatten();
+    /// Check the payload against the header checksum; version 1 files have none and pass.
+    pub fn verify_checksum(&self) -> Result<()> {
+        if !self.header.has_checksum() {
+            return Ok(());
+        }
+        let actual = crc32fast::hash(&self.map[self.header.data_offset()..]);
+        if actual != self.header.checksum {
+            bail!(
+                "checksum mismatch in {}: header {:#010x}, payload {actual:#010x}",
+                self.path.display(),
+                self.header.checksum
+            );
+        }
+        Ok(())
+    }
+
+    /// The permutation embedded after the blocks, if any.
+    pub fn embedded_perm(&self) -> Option<Vec<usize>> {
+        if !self.header.has_embedded_perm() {
+            return None;
+        }
+        let start = self.header.perm_offset as usize;
+        let end = start + 4 * self.header.perm_len as usize;
+        Some(perm_from_le_bytes(&self.map[start..end]))
+    }
+
+    pub fn rows(&self) -> usize {
+        self.header.out as usize
+    }
+
+    pub fn k(&self) -> usize {
+        self.header.k as usize
+    }
+}
 
-    Ok((blocks, hdr.out as usize, hdr.k as usize, perm))
+/// Map a Q8_K `.q8k` file; see `MappedQuantized::open`.
+pub fn map_q8k_tensor(path: &Path) -> Result<MappedQ8K> {
+    MappedQuantized::open(path)
 }
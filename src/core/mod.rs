@@ -1,13 +1,52 @@
 //! Core quantization types and functionality.
 
+pub mod calibration;
+pub mod dequantize;
+pub mod gguf;
+pub mod gptq;
 pub mod header;
+pub mod inspect;
 pub mod io;
+pub mod quality;
+pub mod report;
+pub mod source;
 pub mod validation;
+pub mod verify;
 
-pub use header::{Q8KHeader, DTYPE_Q8K, MAGIC_Q8K, VERSION};
-pub use io::{load_perm, load_q8k_tensor, write_perm, write_q8k};
-pub use validation::{validate_quantization, validate_quantization_direct};
+pub use calibration::{ActivationStats, CalibrationInputs, HessianStore};
+pub use dequantize::{dequantize_model, DequantizeConfig, DequantizeResult};
+pub use gguf::GgufWriter;
+pub use gptq::quantize_rows_q8k_gptq;
+pub use header::{
+    Q8KHeader, Q8KHeaderV2, Q8KMetadata, QuantFormat, DTYPE_Q2K, DTYPE_Q3K, DTYPE_Q4K, DTYPE_Q5K,
+    DTYPE_Q6K, DTYPE_Q8K, FLAG_EMBEDDED_PERM, MAGIC_Q8K, VERSION, VERSION_V1,
+};
+pub use inspect::{
+    inspect_perm, inspect_quantized, BlockSums, PermInspection, QuantizedFileInspection, ValueStats,
+};
+pub use io::{
+    load_perm, load_q8k_tensor, load_quantized, load_scales, map_q8k_tensor, read_q8k_header,
+    write_perm, write_q8k, write_quantized, write_quantized_with, write_scales, MappedQ8K,
+    MappedQuantized, Q8KTensor, QuantizedTensor,
+};
+pub use quality::{
+    check_quality_gates, parse_thresholds, GateFailure, GateMode, MetricThresholds,
+    QualityGateError, ThresholdOverride,
+};
+pub use report::{
+    skip_reason, BaselineComparison, SkipReason, TensorReport, TensorTimings, REPORT_CSV,
+    REPORT_JSON,
+};
+pub use source::{ModelSource, SourceTensor};
+pub use validation::{
+    end_to_end_error, monte_carlo_error, reconstruction_error, run_validation, validate_end_to_end,
+    validate_quantization, validate_quantization_direct, EndToEndError, MonteCarloConfig,
+    MonteCarloError, MonteCarloInputs, ReconstructionError, SourceColumns, TensorMetrics,
+    ValidationConfig, ValidationMetric,
+};
+pub use verify::{verify_quantized, VerifyResult};
 
+use serde::Serialize;
 use std::path::PathBuf;
 
 #[derive(Debug, Clone)]
@@ -17,6 +56,21 @@ pub struct QuantizationConfig {
     pub skip_patterns: Vec<String>,
     pub output_dir: PathBuf,
     pub attention_aware: bool,
+    pub gptq: Option<GptqConfig>,
+    pub format: QuantFormat,
+    pub output_layout: OutputLayout,
+    /// Upper bound in bytes on the estimated working set of the tensors being quantized.
+    /// A single tensor above it is reported before anything is written; concurrent workers
+    /// wait until their tensors fit together. `None` means unbounded.
+    pub memory_budget: Option<u64>,
+    /// Worker threads quantizing tensors concurrently; `None` uses every available core
+    pub threads: Option<usize>,
+    pub validation: ValidationConfig,
+    /// Write `quantization_report.json` and `.csv` into `output_dir`
+    pub report: bool,
+    /// Also round each tensor without the strategy's permutation and keep the permutation
+    /// only when it lowers the weight error
+    pub compare_baseline: bool,
 }
 
 impl Default for QuantizationConfig {
@@ -24,17 +78,75 @@ impl Default for QuantizationConfig {
         Self {
             strategy_type: crate::strategies::StrategyType::L2Norm,
             use_permutation: false,
-            skip_patterns: vec!["embed_tokens".to_string(), "norm".to_string()],
+            skip_patterns: vec![
+                "embed_tokens".to_string(),
+                "token_embd".to_string(),
+                "norm".to_string(),
+            ],
             output_dir: PathBuf::from("./quantized"),
             attention_aware: false,
+            gptq: None,
+            format: QuantFormat::Q8K,
+            output_layout: OutputLayout::PerTensor,
+            memory_budget: None,
+            threads: None,
+            validation: ValidationConfig::default(),
+            report: true,
+            compare_baseline: false,
         }
     }
 }
 
-#[derive(Debug)]
+/// How quantized tensors are written to `output_dir`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputLayout {
+    /// One `<name>.q8k` file per tensor, with its permutation embedded, plus a `.scale`
+    /// sidecar for scaled tensors
+    #[default]
+    PerTensor,
+    /// A single `<input stem>.gguf` with quantized and passthrough tensors and metadata
+    Gguf,
+}
+
+/// Settings for the optional GPTQ error-compensating quantization pass.
+#[derive(Debug, Clone)]
+pub struct GptqConfig {
+    /// Calibration safetensors with `<module>.hessian` or `<module>.inputs` tensors
+    pub calibration_path: PathBuf,
+    /// Diagonal dampening as a fraction of the mean Hessian diagonal
+    pub damp_percent: f64,
+}
+
+impl GptqConfig {
+    pub fn new(calibration_path: impl Into<PathBuf>) -> Self {
+        Self {
+            calibration_path: calibration_path.into(),
+            damp_percent: 0.01,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct QuantizationResult {
+    pub input: PathBuf,
+    pub format: String,
+    /// Permutation strategy; `None` when permutation was off
+    pub strategy: Option<String>,
+    pub threads: usize,
     pub quantized_tensors: usize,
     pub skipped_tensors: usize,
     pub total_time_seconds: f32,
-    pub mse_stats: Vec<(String, f32, f32)>,
+    /// Every source tensor in model order, quantized or passed through
+    pub tensors: Vec<TensorReport>,
+    /// Breached quality gates; only returned here in `GateMode::Warn`
+    pub gate_failures: Vec<GateFailure>,
+    /// Peak resident set size of the process in bytes, where the platform reports it
+    pub peak_rss_bytes: Option<u64>,
+}
+
+impl QuantizationResult {
+    /// Validation results of the quantized tensors, in model order.
+    pub fn metrics(&self) -> impl Iterator<Item = &TensorMetrics> {
+        self.tensors.iter().filter_map(|t| t.metrics.as_ref())
+    }
 }
@@ -0,0 +1,164 @@
+//! Quality gates: thresholds on the validation metrics that can fail a run.
+//!
+//! Every validated tensor is checked against its per-tensor thresholds (a name pattern
+//! override, else the configured threshold, else the metric default), and the model as a whole
+//! against global thresholds on the mean of each metric. In `GateMode::Fail` any breach turns
+//! into a `QualityGateError` once the output is written.
+
+use super::validation::{TensorMetrics, ValidationConfig, ValidationMetric};
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::Serialize;
+use std::fmt;
+
+/// Thresholds by metric, as in `ValidationConfig::thresholds`.
+pub type MetricThresholds = Vec<(ValidationMetric, f32)>;
+
+/// What a breached threshold does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GateMode {
+    /// Log the offenders and carry on
+    #[default]
+    Warn,
+    /// Return a `QualityGateError` listing the offenders
+    Fail,
+}
+
+impl GateMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "warn" => Some(GateMode::Warn),
+            "fail" => Some(GateMode::Fail),
+            _ => None,
+        }
+    }
+}
+
+/// A per-tensor threshold that applies to tensors whose name matches `pattern`.
+#[derive(Debug, Clone)]
+pub struct ThresholdOverride {
+    pub pattern: Regex,
+    pub metric: ValidationMetric,
+    pub threshold: f32,
+}
+
+impl ThresholdOverride {
+    pub fn new(pattern: &str, metric: ValidationMetric, threshold: f32) -> Result<Self> {
+        Ok(Self {
+            pattern: Regex::new(pattern)
+                .with_context(|| format!("invalid tensor pattern {pattern:?}"))?,
+            metric,
+            threshold,
+        })
+    }
+}
+
+/// One breached threshold.
+#[derive(Debug, Clone, Serialize)]
+pub struct GateFailure {
+    /// Offending tensor; `None` for a global threshold
+    pub tensor: Option<String>,
+    pub metric: ValidationMetric,
+    pub value: f32,
+    pub threshold: f32,
+}
+
+impl fmt::Display for GateFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let side = if self.metric.higher_is_better() {
+            "below"
+        } else {
+            "above"
+        };
+        write!(
+            f,
+            "{}: {} {:.6e} {side} threshold {:.1e}",
+            self.tensor.as_deref().unwrap_or("<model mean>"),
+            self.metric.name(),
+            self.value,
+            self.threshold
+        )
+    }
+}
+
+/// Returned by `run_quantization` when quality gates fail in `GateMode::Fail`.
+#[derive(Debug, Clone)]
+pub struct QualityGateError {
+    pub failures: Vec<GateFailure>,
+}
+
+impl fmt::Display for QualityGateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} quality gate(s) failed:", self.failures.len())?;
+        for failure in &self.failures {
+            write!(f, "\n  {failure}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for QualityGateError {}
+
+/// Check every tensor against its thresholds and the model means against the global ones.
+pub fn check_quality_gates<'a>(
+    config: &ValidationConfig,
+    metrics: impl Iterator<Item = &'a TensorMetrics> + Clone,
+) -> Vec<GateFailure> {
+    let mut failures: Vec<GateFailure> = metrics
+        .clone()
+        .flat_map(|m| {
+            m.exceeded(config)
+                .into_iter()
+                .map(|(metric, value, threshold)| GateFailure {
+                    tensor: Some(m.name.clone()),
+                    metric,
+                    value,
+                    threshold,
+                })
+        })
+        .collect();
+    for &(metric, threshold) in &config.global_thresholds {
+        let values: Vec<f32> = metrics.clone().filter_map(|m| m.get(metric)).collect();
+        if values.is_empty() {
+            continue;
+        }
+        let mean = values.iter().map(|&v| v as f64).sum::<f64>() / values.len() as f64;
+        let value = mean as f32;
+        if metric.exceeds(value, threshold) {
+            failures.push(GateFailure {
+                tensor: None,
+                metric,
+                value,
+                threshold,
+            });
+        }
+    }
+    failures
+}
+
+/// Parse a threshold list such as `matmul=1e-3,sqnr=20,down_proj:matmul=5e-2`. Entries with
+/// a `<pattern>:` prefix become overrides for the tensors matching that regex.
+pub fn parse_thresholds(spec: &str) -> Result<(MetricThresholds, Vec<ThresholdOverride>)> {
+    let mut thresholds = Vec::new();
+    let mut overrides = Vec::new();
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((key, value)) = entry.rsplit_once('=') else {
+            bail!("threshold {entry:?} is not <metric>=<value>");
+        };
+        let threshold: f32 = value
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid threshold value {value:?}"))?;
+        let (pattern, metric) = match key.rsplit_once(':') {
+            Some((pattern, metric)) => (Some(pattern), metric),
+            None => (None, key),
+        };
+        let metric = ValidationMetric::from_name(metric.trim())
+            .with_context(|| format!("unknown validation metric {metric:?}"))?;
+        match pattern {
+            Some(pattern) => overrides.push(ThresholdOverride::new(pattern, metric, threshold)?),
+            None => thresholds.push((metric, threshold)),
+        }
+    }
+    Ok((thresholds, overrides))
+}
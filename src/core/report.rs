@@ -0,0 +1,239 @@
+//! Machine-readable quantization report.
+//!
+//! `QuantizationResult` serializes to JSON as is; the CSV flavour has one row per tensor with
+//! the metrics flattened into columns. Both are written to the output directory unless
+//! `QuantizationConfig::report` is off.
+
+use super::validation::TensorMetrics;
+use super::{QuantizationResult, SourceTensor};
+use anyhow::{Context, Result};
+use candle_core::quantized::k_quants::QK_K;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+pub const REPORT_JSON: &str = "quantization_report.json";
+pub const REPORT_CSV: &str = "quantization_report.csv";
+
+/// Why a tensor was stored without quantization.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Not a 2-D tensor
+    NotMatrix,
+    /// Name does not end in `.weight`
+    NotWeight,
+    /// Name contains one of `QuantizationConfig::skip_patterns`
+    SkipPattern(String),
+    /// Inner dimension is not a multiple of the k-quant block size
+    InnerDim { k: usize, block: usize },
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::NotMatrix => write!(f, "not a matrix"),
+            SkipReason::NotWeight => write!(f, "not a weight"),
+            SkipReason::SkipPattern(pattern) => write!(f, "skip pattern {pattern:?}"),
+            SkipReason::InnerDim { k, block } => write!(f, "k = {k} not a multiple of {block}"),
+        }
+    }
+}
+
+impl Serialize for SkipReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Why `tensor` is passed through rather than quantized, if it is. The test of which
+/// tensors get quantized, shared by quantization, comparison and dequantization.
+pub fn skip_reason(tensor: &SourceTensor, skip_patterns: &[String]) -> Option<SkipReason> {
+    if tensor.shape.len() != 2 {
+        return Some(SkipReason::NotMatrix);
+    }
+    if !tensor.name.ends_with(".weight") {
+        return Some(SkipReason::NotWeight);
+    }
+    if let Some(pattern) = skip_patterns
+        .iter()
+        .find(|p| tensor.name.contains(p.as_str()))
+    {
+        return Some(SkipReason::SkipPattern(pattern.clone()));
+    }
+    let k = tensor.shape[1];
+    (!k.is_multiple_of(QK_K)).then_some(SkipReason::InnerDim { k, block: QK_K })
+}
+
+/// Wall-clock seconds spent on one quantized tensor, by stage.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TensorTimings {
+    /// Reading (and dequantizing) the source weights
+    pub load_seconds: f32,
+    pub strategy_seconds: f32,
+    pub quantize_seconds: f32,
+    pub validation_seconds: f32,
+    /// Everything above plus writing, from admission to the last sidecar
+    pub total_seconds: f32,
+}
+
+/// Weight error of one tensor with and without its permutation, measured in the original
+/// column space (scales divided back out). Both orderings go through the quantizer the tensor
+/// is written with: GPTQ when it has a Hessian, plain rounding otherwise.
+#[derive(Debug, Clone, Serialize)]
+pub struct BaselineComparison {
+    pub unpermuted_mse: f32,
+    pub permuted_mse: f32,
+    /// Relative error reduction from the permutation, `1 - permuted / unpermuted`
+    pub improvement: f32,
+    /// False when the permutation made things worse and was dropped
+    pub permutation_kept: bool,
+    /// True when both errors are of GPTQ rather than plain rounding
+    pub gptq: bool,
+}
+
+/// What happened to one source tensor.
+#[derive(Debug, Clone, Serialize)]
+pub struct TensorReport {
+    pub name: String,
+    pub shape: Vec<usize>,
+    /// Source dtype (`F16`, `Q8_0`, ...); `None` for non-float safetensors
+    pub source_dtype: Option<String>,
+    /// Set when the tensor was passed through instead of quantized
+    pub skip_reason: Option<SkipReason>,
+    /// Output block format of a quantized tensor
+    pub format: Option<String>,
+    /// Strategy that ordered the columns; `None` when unpermuted
+    pub strategy: Option<String>,
+    /// Candidates `Auto` did not start on this tensor because its time budget had run out
+    pub skipped_candidates: Vec<String>,
+    /// FNV-1a hash of the column permutation, as 16 hex digits
+    pub perm_hash: Option<String>,
+    /// Whether per-input-channel scales were written
+    pub scaled: bool,
+    pub timings: Option<TensorTimings>,
+    /// Set when `QuantizationConfig::compare_baseline` is on and a strategy ran
+    pub baseline: Option<BaselineComparison>,
+    pub metrics: Option<TensorMetrics>,
+}
+
+impl TensorReport {
+    pub fn is_quantized(&self) -> bool {
+        self.skip_reason.is_none()
+    }
+}
+
+const CSV_HEADER: &[&str] = &[
+    "name",
+    "shape",
+    "source_dtype",
+    "skip_reason",
+    "format",
+    "strategy",
+    "skipped_candidates",
+    "perm_hash",
+    "scaled",
+    "load_seconds",
+    "strategy_seconds",
+    "quantize_seconds",
+    "validation_seconds",
+    "total_seconds",
+    "baseline_unpermuted_mse",
+    "baseline_permuted_mse",
+    "baseline_improvement",
+    "permutation_kept",
+    "baseline_gptq",
+    "validated_rows",
+    "matmul_mse",
+    "direct_mse",
+    "mse",
+    "max_abs_error",
+    "sqnr_db",
+    "min_row_cosine",
+    "mean_row_cosine",
+    "end_to_end_error",
+    "mc_mean_mse",
+    "mc_worst_row_mse",
+    "mc_p99_abs_error",
+];
+
+impl QuantizationResult {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// One row per source tensor; empty cells where a value does not apply.
+    pub fn to_csv(&self) -> String {
+        let mut csv = CSV_HEADER.join(",");
+        csv.push('\n');
+        for t in &self.tensors {
+            let opt = |v: Option<f32>| v.map(|v| v.to_string()).unwrap_or_default();
+            let shape: Vec<String> = t.shape.iter().map(|d| d.to_string()).collect();
+            let timings = t.timings.as_ref();
+            let m = t.metrics.as_ref();
+            let row = [
+                t.name.clone(),
+                shape.join("x"),
+                t.source_dtype.clone().unwrap_or_default(),
+                t.skip_reason
+                    .as_ref()
+                    .map(|r| r.to_string())
+                    .unwrap_or_default(),
+                t.format.clone().unwrap_or_default(),
+                t.strategy.clone().unwrap_or_default(),
+                t.skipped_candidates.join(";"),
+                t.perm_hash.clone().unwrap_or_default(),
+                t.scaled.to_string(),
+                opt(timings.map(|s| s.load_seconds)),
+                opt(timings.map(|s| s.strategy_seconds)),
+                opt(timings.map(|s| s.quantize_seconds)),
+                opt(timings.map(|s| s.validation_seconds)),
+                opt(timings.map(|s| s.total_seconds)),
+                opt(t.baseline.as_ref().map(|b| b.unpermuted_mse)),
+                opt(t.baseline.as_ref().map(|b| b.permuted_mse)),
+                opt(t.baseline.as_ref().map(|b| b.improvement)),
+                t.baseline
+                    .as_ref()
+                    .map(|b| b.permutation_kept.to_string())
+                    .unwrap_or_default(),
+                t.baseline
+                    .as_ref()
+                    .map(|b| b.gptq.to_string())
+                    .unwrap_or_default(),
+                m.map(|m| m.validated_rows.to_string()).unwrap_or_default(),
+                opt(m.and_then(|m| m.matmul_mse)),
+                opt(m.and_then(|m| m.direct_mse)),
+                opt(m.and_then(|m| m.mse)),
+                opt(m.and_then(|m| m.max_abs_error)),
+                opt(m.and_then(|m| m.sqnr_db)),
+                opt(m.and_then(|m| m.min_row_cosine)),
+                opt(m.and_then(|m| m.mean_row_cosine)),
+                opt(m.and_then(|m| m.end_to_end_error)),
+                opt(m.and_then(|m| m.mc_mean_mse)),
+                opt(m.and_then(|m| m.mc_worst_row_mse)),
+                opt(m.and_then(|m| m.mc_p99_abs_error)),
+            ];
+            let cells: Vec<String> = row.iter().map(|c| csv_cell(c)).collect();
+            csv.push_str(&cells.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// Write `quantization_report.json` and `quantization_report.csv` into `dir`.
+    pub fn write_reports(&self, dir: &Path) -> Result<()> {
+        let json = dir.join(REPORT_JSON);
+        fs::write(&json, self.to_json()?).with_context(|| format!("writing {}", json.display()))?;
+        let csv = dir.join(REPORT_CSV);
+        fs::write(&csv, self.to_csv()).with_context(|| format!("writing {}", csv.display()))?;
+        Ok(())
+    }
+}
+
+fn csv_cell(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
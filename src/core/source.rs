@@ -0,0 +1,477 @@
+//! Model files used as quantization input.
+//!
+//! Besides safetensors, GGUF models are accepted: their tensors may already be quantized
+//! (F16, Q8_0, k-quants, ...) and are dequantized to f32 before going through the strategy
+//! and quantization pipeline again. Sharded safetensors checkpoints are read through their
+//! `model.safetensors.index.json` and presented as one flat list of tensors.
+//!
+//! Files are memory-mapped rather than read, so only the tensor being worked on needs to be
+//! resident; `release` hands its pages back once it is done.
+
+use super::gguf::{GGUF_MAGIC, GGUF_PERM_PREFIX, GGUF_SCALE_PREFIX};
+use crate::utils::tensor_to_f32;
+use anyhow::{bail, Context, Result};
+use candle_core::quantized::gguf_file::{self, Value};
+use candle_core::quantized::k_quants::{
+    BlockQ2K, BlockQ3K, BlockQ4K, BlockQ4_0, BlockQ4_1, BlockQ5K, BlockQ5_0, BlockQ5_1, BlockQ6K,
+    BlockQ8K, BlockQ8_0,
+};
+use candle_core::quantized::{GgmlDType, GgmlType, QStorage, QTensor};
+use candle_core::Device;
+use half::{bf16, f16};
+use memmap2::Mmap;
+use safetensors::tensor::Dtype;
+use safetensors::SafeTensors;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+/// GGUF keys describing the file itself rather than the model; they are rewritten on output.
+const GGUF_FILE_KEYS: [&str; 3] = [
+    "general.alignment",
+    "general.file_type",
+    "general.quantization_version",
+];
+
+/// Shape, element type and location of one input tensor.
+#[derive(Debug, Clone)]
+pub struct SourceTensor {
+    pub name: String,
+    pub shape: Vec<usize>,
+    /// `None` for safetensors dtypes without a ggml equivalent (integers, bool, ...)
+    pub dtype: Option<GgmlDType>,
+    st_dtype: Option<Dtype>,
+    shard: usize,
+    offset: usize,
+    len: usize,
+}
+
+enum Container {
+    /// String metadata of all shards and of the index, if any
+    Safetensors(BTreeMap<String, String>),
+    Gguf(gguf_file::Content),
+}
+
+impl SourceTensor {
+    /// Element type as stored in a safetensors file; `None` for GGUF tensors.
+    pub fn safetensors_dtype(&self) -> Option<Dtype> {
+        self.st_dtype
+    }
+}
+
+/// A memory-mapped safetensors model (single file or sharded) or GGUF model.
+pub struct ModelSource {
+    name: String,
+    shards: Vec<Mmap>,
+    container: Container,
+    tensors: Vec<SourceTensor>,
+}
+
+impl ModelSource {
+    /// Open a model file, a `*.safetensors.index.json` shard index, or a directory holding
+    /// one of these. GGUF is recognised by its magic, other files are read as safetensors.
+    pub fn open(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            return Self::open_dir(path);
+        }
+        if path.extension().is_some_and(|e| e == "json") {
+            return Self::open_index(path);
+        }
+        let bytes = map_file(path)?;
+        let name = path
+            .file_stem()
+            .map_or("model".into(), |s| s.to_string_lossy().into_owned());
+        if bytes.len() >= 4 && u32::from_le_bytes(bytes[0..4].try_into().unwrap()) == GGUF_MAGIC {
+            Self::from_gguf(name, bytes).with_context(|| format!("parsing gguf {}", path.display()))
+        } else {
+            let mut metadata = BTreeMap::new();
+            let tensors = read_safetensors(&bytes, 0, &mut metadata)
+                .with_context(|| format!("parsing safetensors {}", path.display()))?;
+            Ok(Self::new(
+                name,
+                vec![bytes],
+                Container::Safetensors(metadata),
+                tensors,
+            ))
+        }
+    }
+
+    /// Use the shard index in `dir`, or its only safetensors / GGUF file.
+    fn open_dir(dir: &Path) -> Result<Self> {
+        let mut indexes = Vec::new();
+        let mut models = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            if file_name.ends_with(".safetensors.index.json") {
+                indexes.push(path);
+            } else if file_name.ends_with(".safetensors") || file_name.ends_with(".gguf") {
+                models.push(path);
+            }
+        }
+        match (indexes.as_slice(), models.as_slice()) {
+            ([index], _) => Self::open_index(index),
+            ([], [model]) => Self::open(model),
+            ([], []) => bail!("no safetensors index or model file in {}", dir.display()),
+            ([], _) => bail!(
+                "{} holds {} model files but no *.safetensors.index.json",
+                dir.display(),
+                models.len()
+            ),
+            _ => bail!("{} holds several shard indexes", dir.display()),
+        }
+    }
+
+    /// Read every shard listed in the `weight_map` of a safetensors index.
+    fn open_index(path: &Path) -> Result<Self> {
+        let index: serde_json::Value = serde_json::from_slice(
+            &fs::read(path).with_context(|| format!("reading {}", path.display()))?,
+        )
+        .with_context(|| format!("parsing {}", path.display()))?;
+        let weight_map = index
+            .get("weight_map")
+            .and_then(|m| m.as_object())
+            .with_context(|| format!("no weight_map in {}", path.display()))?;
+
+        let mut files = Vec::with_capacity(weight_map.len());
+        for (tensor, file) in weight_map {
+            let file = file
+                .as_str()
+                .with_context(|| format!("weight_map entry of {tensor} is not a file name"))?;
+            files.push(file);
+        }
+        files.sort_unstable();
+        files.dedup();
+
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let mut metadata = BTreeMap::new();
+        if let Some(index_meta) = index.get("metadata").and_then(|m| m.as_object()) {
+            for (key, value) in index_meta {
+                let value = match value.as_str() {
+                    Some(s) => s.to_string(),
+                    None => value.to_string(),
+                };
+                metadata.insert(key.clone(), value);
+            }
+        }
+        let mut shards = Vec::with_capacity(files.len());
+        let mut tensors = Vec::new();
+        for (shard, file) in files.iter().enumerate() {
+            let shard_path = dir.join(file);
+            let bytes = map_file(&shard_path)?;
+            tensors.extend(
+                read_safetensors(&bytes, shard, &mut metadata)
+                    .with_context(|| format!("parsing shard {}", shard_path.display()))?,
+            );
+            shards.push(bytes);
+        }
+
+        // Every tensor once, and in the shard the index says.
+        let mut seen = HashMap::with_capacity(tensors.len());
+        for t in &tensors {
+            if seen.insert(t.name.as_str(), t.shard).is_some() {
+                bail!("tensor {} appears in more than one shard", t.name);
+            }
+        }
+        for (tensor, file) in weight_map {
+            let file = file.as_str().unwrap_or_default();
+            match seen.get(tensor.as_str()) {
+                Some(&shard) if files[shard] == file => {}
+                Some(&shard) => bail!(
+                    "index puts {tensor} in {file}, but it is stored in {}",
+                    files[shard]
+                ),
+                None => bail!("index lists {tensor} in {file}, but no shard holds it"),
+            }
+        }
+
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let name = file_name
+            .strip_suffix(".safetensors.index.json")
+            .or_else(|| file_name.strip_suffix(".json"))
+            .unwrap_or(&file_name)
+            .to_string();
+        println!("Shards : {} ({})", shards.len(), path.display());
+        Ok(Self::new(
+            name,
+            shards,
+            Container::Safetensors(metadata),
+            tensors,
+        ))
+    }
+
+    fn from_gguf(name: String, bytes: Mmap) -> Result<Self> {
+        let content = gguf_file::Content::read(&mut Cursor::new(&bytes))?;
+        let mut tensors = Vec::new();
+        for (tensor_name, info) in &content.tensor_infos {
+            let shape = info.shape.dims().to_vec();
+            let elems: usize = shape.iter().product();
+            let dtype = info.ggml_dtype;
+            if !elems.is_multiple_of(dtype.block_size()) {
+                bail!("tensor {tensor_name}: {elems} elements do not fill {dtype:?} blocks");
+            }
+            let offset = (content.tensor_data_offset + info.offset) as usize;
+            let len = elems / dtype.block_size() * dtype.type_size();
+            if offset + len > bytes.len() {
+                bail!("tensor {tensor_name} extends past the end of the file");
+            }
+            tensors.push(SourceTensor {
+                name: tensor_name.clone(),
+                shape,
+                dtype: Some(dtype),
+                st_dtype: None,
+                shard: 0,
+                offset,
+                len,
+            });
+        }
+        Ok(Self::new(
+            name,
+            vec![bytes],
+            Container::Gguf(content),
+            tensors,
+        ))
+    }
+
+    /// Order tensors as they are laid out in the shards.
+    fn new(
+        name: String,
+        shards: Vec<Mmap>,
+        container: Container,
+        mut tensors: Vec<SourceTensor>,
+    ) -> Self {
+        tensors.sort_by_key(|t| (t.shard, t.offset));
+        Self {
+            name,
+            shards,
+            container,
+            tensors,
+        }
+    }
+
+    /// Model name derived from the input path, e.g. `model` for `model.safetensors.index.json`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Number of files the tensors are spread over.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn is_gguf(&self) -> bool {
+        matches!(self.container, Container::Gguf(_))
+    }
+
+    pub fn tensors(&self) -> &[SourceTensor] {
+        &self.tensors
+    }
+
+    pub fn len(&self) -> usize {
+        self.tensors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tensors.is_empty()
+    }
+
+    /// String metadata of a safetensors model; `None` for GGUF.
+    pub fn safetensors_metadata(&self) -> Option<&BTreeMap<String, String>> {
+        match self.container {
+            Container::Safetensors(ref metadata) => Some(metadata),
+            Container::Gguf(_) => None,
+        }
+    }
+
+    /// Column permutation a GGUF file written by this crate stores for `name` under
+    /// `quantize.perm.`; always `None` for safetensors.
+    pub fn column_perm(&self, name: &str) -> Result<Option<Vec<usize>>> {
+        self.kv_array(&format!("{GGUF_PERM_PREFIX}{name}"), |v| {
+            v.to_u32().map(|p| p as usize)
+        })
+    }
+
+    /// Per-input-channel scales a GGUF file written by this crate stores for `name` under
+    /// `quantize.scale.`; always `None` for safetensors.
+    pub fn column_scales(&self, name: &str) -> Result<Option<Vec<f32>>> {
+        self.kv_array(&format!("{GGUF_SCALE_PREFIX}{name}"), Value::to_f32)
+    }
+
+    fn kv_array<T>(
+        &self,
+        key: &str,
+        element: impl Fn(&Value) -> candle_core::Result<T>,
+    ) -> Result<Option<Vec<T>>> {
+        let Container::Gguf(ref content) = self.container else {
+            return Ok(None);
+        };
+        let Some(value) = content.metadata.get(key) else {
+            return Ok(None);
+        };
+        let values = value
+            .to_vec()?
+            .iter()
+            .map(element)
+            .collect::<candle_core::Result<_>>()
+            .with_context(|| format!("reading {key}"))?;
+        Ok(Some(values))
+    }
+
+    /// Raw little-endian tensor bytes, in the tensor's own (possibly quantized) format.
+    pub fn raw(&self, tensor: &SourceTensor) -> &[u8] {
+        &self.shards[tensor.shard][tensor.offset..tensor.offset + tensor.len]
+    }
+
+    /// Drop the resident pages of a tensor that has been processed. They are re-read from
+    /// disk if the tensor is accessed again.
+    pub fn release(&self, tensor: &SourceTensor) {
+        let shard = &self.shards[tensor.shard];
+        release_pages(shard, &shard[tensor.offset..tensor.offset + tensor.len]);
+    }
+
+    /// Tensor values as f32, dequantizing ggml block formats.
+    pub fn tensor_f32(&self, tensor: &SourceTensor) -> Result<Vec<f32>> {
+        let raw = self.raw(tensor);
+        if let Some(dtype) = tensor.st_dtype {
+            return tensor_to_f32(raw, dtype);
+        }
+        let elems: usize = tensor.shape.iter().product();
+        let Some(dtype) = tensor.dtype else {
+            bail!("tensor {} has no element type", tensor.name);
+        };
+        Ok(match dtype {
+            GgmlDType::F32 => dequantize::<f32>(raw, elems),
+            GgmlDType::F16 => dequantize::<f16>(raw, elems),
+            GgmlDType::BF16 => dequantize::<bf16>(raw, elems),
+            GgmlDType::Q4_0 => dequantize::<BlockQ4_0>(raw, elems),
+            GgmlDType::Q4_1 => dequantize::<BlockQ4_1>(raw, elems),
+            GgmlDType::Q5_0 => dequantize::<BlockQ5_0>(raw, elems),
+            GgmlDType::Q5_1 => dequantize::<BlockQ5_1>(raw, elems),
+            GgmlDType::Q8_0 => dequantize::<BlockQ8_0>(raw, elems),
+            GgmlDType::Q2K => dequantize::<BlockQ2K>(raw, elems),
+            GgmlDType::Q3K => dequantize::<BlockQ3K>(raw, elems),
+            GgmlDType::Q4K => dequantize::<BlockQ4K>(raw, elems),
+            GgmlDType::Q5K => dequantize::<BlockQ5K>(raw, elems),
+            GgmlDType::Q6K => dequantize::<BlockQ6K>(raw, elems),
+            GgmlDType::Q8K => dequantize::<BlockQ8K>(raw, elems),
+            GgmlDType::Q8_1 => bail!("cannot dequantize Q8_1 tensor {}", tensor.name),
+        })
+    }
+
+    /// The tensor as a `QTensor` on `device`. GGUF tensors keep their ggml type, safetensors
+    /// tensors are converted to F32.
+    pub fn qtensor(&self, tensor: &SourceTensor, device: &Device) -> Result<QTensor> {
+        match self.container {
+            Container::Gguf(ref content) => Ok(content.tensor(
+                &mut Cursor::new(&self.shards[tensor.shard][..]),
+                &tensor.name,
+                device,
+            )?),
+            Container::Safetensors(_) => {
+                let values = self.tensor_f32(tensor)?;
+                let storage = QStorage::from_data(
+                    Cow::Borrowed(bytemuck::cast_slice(&values)),
+                    device,
+                    GgmlDType::F32,
+                )?;
+                Ok(QTensor::new(storage, tensor.shape.as_slice())?)
+            }
+        }
+    }
+
+    /// Model metadata to carry over into GGUF output. Safetensors string metadata is
+    /// prefixed with `safetensors.`; GGUF keys are kept except file-level and
+    /// permutation/scale entries, which the new output rewrites.
+    pub fn metadata(&self) -> Vec<(String, Value)> {
+        let mut entries: Vec<(String, Value)> = match self.container {
+            Container::Safetensors(ref metadata) => metadata
+                .iter()
+                .map(|(k, v)| (format!("safetensors.{k}"), Value::String(v.clone())))
+                .collect(),
+            Container::Gguf(ref content) => content
+                .metadata
+                .iter()
+                .filter(|(k, _)| {
+                    !GGUF_FILE_KEYS.contains(&k.as_str())
+                        && !k.starts_with("quantize.")
+                        && !k.starts_with(GGUF_PERM_PREFIX)
+                        && !k.starts_with(GGUF_SCALE_PREFIX)
+                })
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        };
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+}
+
+pub(super) fn map_file(path: &Path) -> Result<Mmap> {
+    let file = fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    // SAFETY: input files must not be modified while they are being quantized.
+    unsafe { Mmap::map(&file) }.with_context(|| format!("mapping {}", path.display()))
+}
+
+/// Drop the resident pages of `data`, a range of `map`. They are re-read from disk if the
+/// range is accessed again.
+pub(super) fn release_pages(map: &Mmap, data: &[u8]) {
+    #[cfg(unix)]
+    {
+        use memmap2::UncheckedAdvice;
+        let offset = data.as_ptr() as usize - map.as_ptr() as usize;
+        // SAFETY: the map is read-only and file-backed, so MADV_DONTNEED only evicts pages
+        // that are transparently faulted back in from the file.
+        let _ =
+            unsafe { map.unchecked_advise_range(UncheckedAdvice::DontNeed, offset, data.len()) };
+    }
+    #[cfg(not(unix))]
+    let _ = (map, data);
+}
+
+/// Tensor table of one safetensors file; its string metadata is merged into `metadata`.
+fn read_safetensors(
+    bytes: &[u8],
+    shard: usize,
+    metadata: &mut BTreeMap<String, String>,
+) -> Result<Vec<SourceTensor>> {
+    let (header_len, header) = SafeTensors::read_metadata(bytes)?;
+    let data_start = 8 + header_len;
+    if let Some(meta) = header.metadata() {
+        metadata.extend(meta.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    Ok(header
+        .tensors()
+        .into_iter()
+        .map(|(name, info)| {
+            let (start, end) = info.data_offsets;
+            let dtype = match info.dtype {
+                Dtype::F32 => Some(GgmlDType::F32),
+                Dtype::F16 => Some(GgmlDType::F16),
+                Dtype::BF16 => Some(GgmlDType::BF16),
+                _ => None,
+            };
+            SourceTensor {
+                name,
+                shape: info.shape.clone(),
+                dtype,
+                st_dtype: Some(info.dtype),
+                shard,
+                offset: data_start + start,
+                len: end - start,
+            }
+        })
+        .collect())
+}
+
+/// Decode `elems` values stored as consecutive blocks of `T`.
+fn dequantize<T: GgmlType>(raw: &[u8], elems: usize) -> Vec<f32> {
+    let mut blocks = vec![T::zeros(); elems / T::BLCK_SIZE];
+    // `raw` has no alignment guarantee, so copy into properly aligned blocks first.
+    unsafe {
+        std::ptr::copy_nonoverlapping(raw.as_ptr(), blocks.as_mut_ptr() as *mut u8, raw.len());
+    }
+    let mut out = vec![0f32; elems];
+    T::to_float(&blocks, &mut out);
+    out
+}
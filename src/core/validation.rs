@@ -1,10 +1,746 @@
 //! Quantization quality validation functions.
 
-use anyhow::Result;
-use candle_core::quantized::k_quants::{matmul, BlockQ8K};
+use super::quality::{GateMode, ThresholdOverride};
+use super::CalibrationInputs;
+use anyhow::{bail, Context, Result};
+use candle_core::quantized::k_quants::matmul;
+use candle_core::quantized::GgmlType;
 use candle_core::Device;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, StandardNormal, StudentT};
+use serde::{Serialize, Serializer};
+use std::path::PathBuf;
 
-pub fn validate_quantization(original: &[f32], blocks: &[BlockQ8K], k: usize) -> Result<f32> {
+/// Random activation vectors fed through the end-to-end check.
+const END_TO_END_BATCH: usize = 4;
+const END_TO_END_SEED: u64 = 0x5eed_0e2e;
+
+/// A validation figure computed for each quantized tensor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ValidationMetric {
+    /// Output MSE of the matmul with an all-ones input vector
+    Matmul,
+    /// Output MSE of the matmul with a gradient input vector
+    Direct,
+    /// Per-element MSE of the dequantized weights
+    Mse,
+    /// Largest absolute error of a dequantized weight
+    MaxAbs,
+    /// Signal-to-quantization-noise ratio of the dequantized weights, in dB
+    Sqnr,
+    /// Cosine similarity between original and dequantized rows (worst row)
+    Cosine,
+    /// Relative output error for random activations in the original column order, through
+    /// the stored permutation and scales
+    EndToEnd,
+    /// Output MSE over a seeded batch of random or calibration input vectors
+    MonteCarlo,
+}
+
+impl ValidationMetric {
+    pub const ALL: [ValidationMetric; 8] = [
+        ValidationMetric::Matmul,
+        ValidationMetric::Direct,
+        ValidationMetric::Mse,
+        ValidationMetric::MaxAbs,
+        ValidationMetric::Sqnr,
+        ValidationMetric::Cosine,
+        ValidationMetric::EndToEnd,
+        ValidationMetric::MonteCarlo,
+    ];
+
+    /// Metrics computed unless configured otherwise; the Monte-Carlo batch is opt-in since it
+    /// costs a full matmul per input vector.
+    pub const DEFAULT: [ValidationMetric; 7] = [
+        ValidationMetric::Matmul,
+        ValidationMetric::Direct,
+        ValidationMetric::Mse,
+        ValidationMetric::MaxAbs,
+        ValidationMetric::Sqnr,
+        ValidationMetric::Cosine,
+        ValidationMetric::EndToEnd,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ValidationMetric::Matmul => "matmul",
+            ValidationMetric::Direct => "direct",
+            ValidationMetric::Mse => "mse",
+            ValidationMetric::MaxAbs => "max_abs",
+            ValidationMetric::Sqnr => "sqnr",
+            ValidationMetric::Cosine => "cosine",
+            ValidationMetric::EndToEnd => "end_to_end",
+            ValidationMetric::MonteCarlo => "monte_carlo",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.name() == name)
+    }
+
+    /// SQNR and cosine similarity get worse as they drop; the error metrics as they grow.
+    pub fn higher_is_better(self) -> bool {
+        matches!(self, ValidationMetric::Sqnr | ValidationMetric::Cosine)
+    }
+
+    /// Whether `value` is on the wrong side of `threshold`.
+    pub fn exceeds(self, value: f32, threshold: f32) -> bool {
+        if self.higher_is_better() {
+            value < threshold
+        } else {
+            value > threshold
+        }
+    }
+
+    /// Warning threshold used when none is configured. The absolute weight errors depend on
+    /// the weight scale, so they have none; a column mismatch in the end-to-end check fails
+    /// the tensor through `ValidationConfig::end_to_end_tolerance` instead.
+    pub fn default_threshold(self) -> Option<f32> {
+        match self {
+            ValidationMetric::Matmul | ValidationMetric::Direct => Some(1e-2),
+            ValidationMetric::Mse
+            | ValidationMetric::MaxAbs
+            | ValidationMetric::EndToEnd
+            | ValidationMetric::MonteCarlo => None,
+            ValidationMetric::Sqnr => Some(15.0),
+            ValidationMetric::Cosine => Some(0.99),
+        }
+    }
+}
+
+impl Serialize for ValidationMetric {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+/// Validation results of one quantized tensor; a metric is `None` when it did not run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TensorMetrics {
+    pub name: String,
+    pub rows: usize,
+    pub k: usize,
+    /// Rows the metrics were computed on (all of them unless sampled)
+    pub validated_rows: usize,
+    pub matmul_mse: Option<f32>,
+    pub direct_mse: Option<f32>,
+    pub mse: Option<f32>,
+    pub max_abs_error: Option<f32>,
+    pub sqnr_db: Option<f32>,
+    pub min_row_cosine: Option<f32>,
+    pub mean_row_cosine: Option<f32>,
+    /// Relative output error measured in the original column order
+    pub end_to_end_error: Option<f32>,
+    /// Monte-Carlo output MSE: mean over all outputs, and of the worst row
+    pub mc_mean_mse: Option<f32>,
+    pub mc_worst_row_mse: Option<f32>,
+    /// 99th percentile of the absolute Monte-Carlo output error
+    pub mc_p99_abs_error: Option<f32>,
+}
+
+impl TensorMetrics {
+    pub fn new(name: &str, rows: usize, k: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            rows,
+            k,
+            ..Default::default()
+        }
+    }
+
+    pub fn get(&self, metric: ValidationMetric) -> Option<f32> {
+        match metric {
+            ValidationMetric::Matmul => self.matmul_mse,
+            ValidationMetric::Direct => self.direct_mse,
+            ValidationMetric::Mse => self.mse,
+            ValidationMetric::MaxAbs => self.max_abs_error,
+            ValidationMetric::Sqnr => self.sqnr_db,
+            ValidationMetric::Cosine => self.min_row_cosine,
+            ValidationMetric::EndToEnd => self.end_to_end_error,
+            ValidationMetric::MonteCarlo => self.mc_mean_mse,
+        }
+    }
+
+    /// Metrics that ran, in `ValidationMetric::ALL` order.
+    pub fn values(&self) -> Vec<(ValidationMetric, f32)> {
+        ValidationMetric::ALL
+            .into_iter()
+            .filter_map(|m| self.get(m).map(|v| (m, v)))
+            .collect()
+    }
+
+    /// Metrics on the wrong side of their threshold, with that threshold.
+    pub fn exceeded(&self, config: &ValidationConfig) -> Vec<(ValidationMetric, f32, f32)> {
+        self.values()
+            .into_iter()
+            .filter_map(|(m, v)| {
+                let t = config.threshold_for(&self.name, m)?;
+                m.exceeds(v, t).then_some((m, v, t))
+            })
+            .collect()
+    }
+
+    /// One-line summary of the metrics that ran.
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(v) = self.matmul_mse {
+            parts.push(format!("MSE (matmul): {v:.6e}"));
+        }
+        if let Some(v) = self.direct_mse {
+            parts.push(format!("MSE (direct): {v:.6e}"));
+        }
+        if let Some(v) = self.mse {
+            parts.push(format!("MSE (weights): {v:.6e}"));
+        }
+        if let Some(v) = self.max_abs_error {
+            parts.push(format!("max |err|: {v:.4e}"));
+        }
+        if let Some(v) = self.sqnr_db {
+            parts.push(format!("SQNR: {v:.2} dB"));
+        }
+        if let (Some(min), Some(mean)) = (self.min_row_cosine, self.mean_row_cosine) {
+            parts.push(format!("cos (min/mean): {min:.6}/{mean:.6}"));
+        }
+        if let Some(v) = self.end_to_end_error {
+            parts.push(format!("end-to-end: {v:.4e}"));
+        }
+        if let (Some(mean), Some(p99), Some(worst)) = (
+            self.mc_mean_mse,
+            self.mc_p99_abs_error,
+            self.mc_worst_row_mse,
+        ) {
+            parts.push(format!(
+                "MC (mean MSE/p99 |err|/worst row MSE): {mean:.4e}/{p99:.4e}/{worst:.4e}"
+            ));
+        }
+        parts.join(", ")
+    }
+}
+
+/// Where the Monte-Carlo input vectors come from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MonteCarloInputs {
+    /// Standard normal activations
+    Gaussian,
+    /// Student-t activations, with outliers like real residual streams
+    HeavyTailed { dof: f32 },
+    /// `<module>.inputs` from a calibration safetensors file; tensors without recorded inputs
+    /// fall back to Gaussian vectors
+    Calibration(PathBuf),
+}
+
+/// Batch drawn for the Monte-Carlo metric.
+#[derive(Debug, Clone)]
+pub struct MonteCarloConfig {
+    pub inputs: MonteCarloInputs,
+    /// Number of input vectors
+    pub samples: usize,
+    pub seed: u64,
+}
+
+impl Default for MonteCarloConfig {
+    fn default() -> Self {
+        Self {
+            inputs: MonteCarloInputs::Gaussian,
+            samples: 64,
+            seed: 0x5eed_0c3c,
+        }
+    }
+}
+
+impl MonteCarloConfig {
+    /// Parse `CANDLE_Q8K_MONTE_CARLO`: `gaussian`, `heavy_tailed` or a calibration
+    /// safetensors path, optionally followed by `:<samples>`.
+    pub fn from_spec(spec: &str) -> Result<Self> {
+        let (inputs, samples) = match spec.trim().rsplit_once(':') {
+            Some((inputs, n)) if n.chars().all(|c| c.is_ascii_digit()) => (
+                inputs,
+                Some(n.parse::<usize>().context("invalid sample count")?),
+            ),
+            _ => (spec.trim(), None),
+        };
+        let inputs = match inputs {
+            "gaussian" => MonteCarloInputs::Gaussian,
+            "heavy_tailed" => MonteCarloInputs::HeavyTailed { dof: 3.0 },
+            "" => bail!("missing monte-carlo inputs"),
+            path => MonteCarloInputs::Calibration(path.into()),
+        };
+        let defaults = Self::default();
+        Ok(Self {
+            inputs,
+            samples: samples.unwrap_or(defaults.samples).max(1),
+            ..defaults
+        })
+    }
+}
+
+/// Which validation runs on each quantized tensor, on how many rows, and which thresholds
+/// gate the result.
+#[derive(Debug, Clone)]
+pub struct ValidationConfig {
+    /// Skip validation entirely when false
+    pub enabled: bool,
+    /// Metrics to compute, in report order
+    pub metrics: Vec<ValidationMetric>,
+    /// Fraction of rows validated for tensors with more than `sample_above_rows` rows
+    pub sample_rate: f32,
+    pub sample_above_rows: usize,
+    /// Per-tensor thresholds by metric (a floor for SQNR and cosine, a ceiling otherwise);
+    /// metrics not listed use `default_threshold`
+    pub thresholds: Vec<(ValidationMetric, f32)>,
+    /// Per-tensor thresholds for tensors matching a name pattern; the first match wins over
+    /// `thresholds`
+    pub threshold_overrides: Vec<ThresholdOverride>,
+    /// Thresholds on the mean of a metric over all validated tensors
+    pub global_thresholds: Vec<(ValidationMetric, f32)>,
+    /// Whether breached thresholds only warn or fail the run
+    pub gate: GateMode,
+    /// How much larger (relative to the output norm) the end-to-end error may be than the
+    /// error of the same blocks against the weights they were quantized from, before the
+    /// tensor fails. Beyond float rounding this gap only comes from a wrong permutation or
+    /// wrong scales.
+    pub end_to_end_tolerance: f32,
+    /// Inputs of the `MonteCarlo` metric
+    pub monte_carlo: MonteCarloConfig,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            metrics: ValidationMetric::DEFAULT.to_vec(),
+            sample_rate: 1.0,
+            sample_above_rows: 4096,
+            thresholds: Vec::new(),
+            threshold_overrides: Vec::new(),
+            global_thresholds: Vec::new(),
+            gate: GateMode::Warn,
+            end_to_end_tolerance: 1e-3,
+            monte_carlo: MonteCarloConfig::default(),
+        }
+    }
+}
+
+impl ValidationConfig {
+    /// No validation at all, for fast production runs.
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Default::default()
+        }
+    }
+
+    /// Parse `CANDLE_Q8K_VALIDATION`: `0`/`false`/`off` disables validation, `1`/`true`/`on`
+    /// keeps the defaults, and a comma separated list (`mse,sqnr,cosine`) picks the metrics.
+    pub fn from_spec(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        match spec.to_ascii_lowercase().as_str() {
+            "0" | "false" | "off" => return Ok(Self::disabled()),
+            "1" | "true" | "on" | "" => return Ok(Self::default()),
+            _ => {}
+        }
+        let mut metrics = Vec::new();
+        for name in spec.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match ValidationMetric::from_name(name) {
+                Some(m) if !metrics.contains(&m) => metrics.push(m),
+                Some(_) => {}
+                None => bail!("unknown validation metric {name:?}"),
+            }
+        }
+        Ok(Self {
+            metrics,
+            ..Default::default()
+        })
+    }
+
+    /// Whether `metric` runs at all.
+    pub fn runs(&self, metric: ValidationMetric) -> bool {
+        self.enabled && self.metrics.contains(&metric)
+    }
+
+    /// Per-tensor threshold of `metric` for the tensor `name`.
+    pub fn threshold_for(&self, name: &str, metric: ValidationMetric) -> Option<f32> {
+        if let Some(o) = self
+            .threshold_overrides
+            .iter()
+            .find(|o| o.metric == metric && o.pattern.is_match(name))
+        {
+            return Some(o.threshold);
+        }
+        self.thresholds
+            .iter()
+            .find(|(m, _)| *m == metric)
+            .map_or(metric.default_threshold(), |&(_, t)| Some(t))
+    }
+
+    /// Rows to validate out of `rows`, evenly spread when sampling applies.
+    pub fn sampled_rows(&self, rows: usize) -> Vec<usize> {
+        if rows <= self.sample_above_rows || self.sample_rate >= 1.0 {
+            return (0..rows).collect();
+        }
+        let n = ((rows as f64 * self.sample_rate.max(0.0) as f64).ceil() as usize).clamp(1, rows);
+        (0..n).map(|i| i * rows / n).collect()
+    }
+}
+
+/// How quantized columns relate to the source weights: quantized column `i` holds source
+/// column `perm[i]` multiplied by `scales[perm[i]]`.
+pub struct SourceColumns<'a> {
+    /// Source weights, `rows x k` in their original column order
+    pub weights: &'a [f32],
+    pub perm: Option<&'a [usize]>,
+    pub scales: Option<&'a [f32]>,
+}
+
+/// Compute the configured metrics of `blocks` against `original` (`rows x k`, the weights in
+/// the column order they were quantized in), on the sampled rows only. `source` defaults to
+/// `original` with identity columns; `calibration` supplies Monte-Carlo inputs when they
+/// come from a file. Fails when the end-to-end check finds the columns do not line up with
+/// the source weights.
+pub fn run_validation<T: GgmlType>(
+    config: &ValidationConfig,
+    name: &str,
+    original: &[f32],
+    blocks: &[T],
+    k: usize,
+    source: Option<&SourceColumns<'_>>,
+    calibration: Option<&CalibrationInputs>,
+) -> Result<TensorMetrics> {
+    let rows = original.len() / k;
+    let mut metrics = TensorMetrics::new(name, rows, k);
+    if !config.enabled || config.metrics.is_empty() {
+        return Ok(metrics);
+    }
+    let picked = config.sampled_rows(rows);
+    metrics.validated_rows = picked.len();
+    let identity = SourceColumns {
+        weights: original,
+        perm: None,
+        scales: None,
+    };
+    let source = source.unwrap_or(&identity);
+    let sampled;
+    let (original, blocks, source_weights) = if picked.len() == rows {
+        (original, blocks, source.weights)
+    } else {
+        sampled = sample_rows(&picked, k, original, blocks, source.weights);
+        (
+            sampled.0.as_slice(),
+            sampled.1.as_slice(),
+            sampled.2.as_slice(),
+        )
+    };
+
+    let wants = |m| config.metrics.contains(&m);
+    if wants(ValidationMetric::Matmul) {
+        metrics.matmul_mse = Some(validate_quantization(original, blocks, k)?);
+    }
+    if wants(ValidationMetric::Direct) {
+        metrics.direct_mse = Some(validate_quantization_direct(original, blocks, k)?);
+    }
+    let dequant = [
+        ValidationMetric::Mse,
+        ValidationMetric::MaxAbs,
+        ValidationMetric::Sqnr,
+        ValidationMetric::Cosine,
+    ];
+    if dequant.into_iter().any(wants) {
+        let r = reconstruction_error(original, blocks, k);
+        if wants(ValidationMetric::Mse) {
+            metrics.mse = Some(r.mse);
+        }
+        if wants(ValidationMetric::MaxAbs) {
+            metrics.max_abs_error = Some(r.max_abs);
+        }
+        if wants(ValidationMetric::Sqnr) {
+            metrics.sqnr_db = Some(r.sqnr_db);
+        }
+        if wants(ValidationMetric::Cosine) {
+            metrics.min_row_cosine = Some(r.min_cosine);
+            metrics.mean_row_cosine = Some(r.mean_cosine);
+        }
+    }
+    let columns = SourceColumns {
+        weights: source_weights,
+        ..*source
+    };
+    if wants(ValidationMetric::MonteCarlo) {
+        let mc = &config.monte_carlo;
+        let inputs = match (&mc.inputs, calibration) {
+            (MonteCarloInputs::Calibration(_), Some(store)) => store.inputs(name, k, mc.samples)?,
+            _ => None,
+        };
+        let e = monte_carlo_error(mc, inputs, blocks, k, &columns)?;
+        metrics.mc_mean_mse = Some(e.mean_mse);
+        metrics.mc_worst_row_mse = Some(e.worst_row_mse);
+        metrics.mc_p99_abs_error = Some(e.p99_abs);
+    }
+    if wants(ValidationMetric::EndToEnd) {
+        metrics.end_to_end_error = Some(checked_end_to_end(
+            config, name, original, blocks, k, &columns,
+        )?);
+    }
+    Ok(metrics)
+}
+
+/// The end-to-end check of `run_validation` on its own, on the sampled rows, for output whose
+/// permutation and scales can only be read back later (GGUF). Returns the relative error.
+pub fn validate_end_to_end<T: GgmlType>(
+    config: &ValidationConfig,
+    name: &str,
+    quantized_from: &[f32],
+    blocks: &[T],
+    k: usize,
+    source: &SourceColumns<'_>,
+) -> Result<f32> {
+    let rows = quantized_from.len() / k;
+    let picked = config.sampled_rows(rows);
+    if picked.len() == rows {
+        return checked_end_to_end(config, name, quantized_from, blocks, k, source);
+    }
+    let (data, blocks, weights) = sample_rows(&picked, k, quantized_from, blocks, source.weights);
+    let columns = SourceColumns {
+        weights: &weights,
+        ..*source
+    };
+    checked_end_to_end(config, name, &data, &blocks, k, &columns)
+}
+
+/// `end_to_end_error`, failing when it exceeds `end_to_end_tolerance`.
+fn checked_end_to_end<T: GgmlType>(
+    config: &ValidationConfig,
+    name: &str,
+    quantized_from: &[f32],
+    blocks: &[T],
+    k: usize,
+    source: &SourceColumns<'_>,
+) -> Result<f32> {
+    let e = end_to_end_error(quantized_from, blocks, k, source)?;
+    if e.excess > config.end_to_end_tolerance {
+        bail!(
+            "{name}: quantized output in the original column order is off by {:.3e} \
+             (relative), {:.3e} beyond the quantization error; the permutation or scales \
+             do not match the weights",
+            e.relative,
+            e.excess
+        );
+    }
+    Ok(e.relative)
+}
+
+/// Rows `picked` of the data, blocks and source weights of a `rows x k` tensor.
+fn sample_rows<T: GgmlType>(
+    picked: &[usize],
+    k: usize,
+    original: &[f32],
+    blocks: &[T],
+    source_weights: &[f32],
+) -> (Vec<f32>, Vec<T>, Vec<f32>) {
+    let blocks_per_row = k / T::BLCK_SIZE;
+    let mut data = Vec::with_capacity(picked.len() * k);
+    let mut qblocks = Vec::with_capacity(picked.len() * blocks_per_row);
+    let mut weights = Vec::with_capacity(picked.len() * k);
+    for &r in picked {
+        data.extend_from_slice(&original[r * k..(r + 1) * k]);
+        qblocks.extend_from_slice(&blocks[r * blocks_per_row..(r + 1) * blocks_per_row]);
+        weights.extend_from_slice(&source_weights[r * k..(r + 1) * k]);
+    }
+    (data, qblocks, weights)
+}
+
+/// Output error of the end-to-end check, relative to the norm of the reference output.
+pub struct EndToEndError {
+    /// `|y_q - W x| / |W x|`
+    pub relative: f32,
+    /// How much `relative` exceeds the error against the weights the blocks were quantized
+    /// from, `|y_q - W' x'| / |W x|`
+    pub excess: f32,
+}
+
+/// Feed seeded random activations `x` through the source weights, and through `blocks` after
+/// gathering them into quantized column order (`x'[i] = x[perm[i]] / scales[perm[i]]`).
+/// `quantized_from` are the `rows x k` weights the blocks were made from.
+pub fn end_to_end_error<T: GgmlType>(
+    quantized_from: &[f32],
+    blocks: &[T],
+    k: usize,
+    source: &SourceColumns<'_>,
+) -> Result<EndToEndError> {
+    let rows = source.weights.len() / k;
+    let mut rng = StdRng::seed_from_u64(END_TO_END_SEED);
+    let x: Vec<f32> = (0..END_TO_END_BATCH * k)
+        .map(|_| rng.random_range(-1.0f32..1.0))
+        .collect();
+    let gathered = gather_inputs(&x, k, source);
+
+    let mut quantized = vec![0f32; END_TO_END_BATCH * rows];
+    matmul::<T>(
+        (END_TO_END_BATCH, k, rows),
+        &gathered,
+        blocks,
+        &mut quantized,
+    )
+    .map_err(|e| anyhow::anyhow!("end-to-end validation matmul failed: {}", e))?;
+
+    let dot =
+        |w: &[f32], x: &[f32]| -> f64 { w.iter().zip(x).map(|(&a, &b)| a as f64 * b as f64).sum() };
+    let (mut reference, mut error, mut quant_error) = (0f64, 0f64, 0f64);
+    for b in 0..END_TO_END_BATCH {
+        let (xs, gs) = (&x[b * k..(b + 1) * k], &gathered[b * k..(b + 1) * k]);
+        for r in 0..rows {
+            let y_ref = dot(&source.weights[r * k..(r + 1) * k], xs);
+            let y_from = dot(&quantized_from[r * k..(r + 1) * k], gs);
+            let y_q = quantized[b * rows + r] as f64;
+            reference += y_ref * y_ref;
+            error += (y_q - y_ref) * (y_q - y_ref);
+            quant_error += (y_q - y_from) * (y_q - y_from);
+        }
+    }
+    let norm = reference.sqrt().max(f64::MIN_POSITIVE);
+    Ok(EndToEndError {
+        relative: (error.sqrt() / norm) as f32,
+        excess: ((error.sqrt() - quant_error.sqrt()) / norm) as f32,
+    })
+}
+
+/// Element-wise error of the dequantized weights.
+pub struct ReconstructionError {
+    pub mse: f32,
+    pub max_abs: f32,
+    /// `10 log10(signal / noise)`; infinite for an exact reconstruction
+    pub sqnr_db: f32,
+    pub min_cosine: f32,
+    pub mean_cosine: f32,
+}
+
+/// Dequantize `blocks` row by row with `GgmlType::to_float` and compare with `original`.
+pub fn reconstruction_error<T: GgmlType>(
+    original: &[f32],
+    blocks: &[T],
+    k: usize,
+) -> ReconstructionError {
+    let rows = original.len() / k;
+    let blocks_per_row = k / T::BLCK_SIZE;
+    let mut restored = vec![0f32; k];
+    let (mut noise, mut signal) = (0f64, 0f64);
+    let mut max_abs = 0f32;
+    let (mut min_cosine, mut sum_cosine) = (1f64, 0f64);
+    for r in 0..rows {
+        T::to_float(
+            &blocks[r * blocks_per_row..(r + 1) * blocks_per_row],
+            &mut restored,
+        );
+        let (mut dot, mut norm_o, mut norm_q) = (0f64, 0f64, 0f64);
+        for (&o, &q) in original[r * k..(r + 1) * k].iter().zip(&restored) {
+            let e = (o - q).abs();
+            max_abs = max_abs.max(e);
+            noise += (e as f64) * (e as f64);
+            let (o, q) = (o as f64, q as f64);
+            signal += o * o;
+            dot += o * q;
+            norm_o += o * o;
+            norm_q += q * q;
+        }
+        // All-zero rows that stay zero are reproduced exactly.
+        let cosine = match (norm_o > 0.0, norm_q > 0.0) {
+            (true, true) => dot / (norm_o.sqrt() * norm_q.sqrt()),
+            (false, false) => 1.0,
+            _ => 0.0,
+        };
+        min_cosine = min_cosine.min(cosine);
+        sum_cosine += cosine;
+    }
+    let n = (rows * k).max(1) as f64;
+    let sqnr_db = if noise == 0.0 {
+        f32::INFINITY
+    } else {
+        (10.0 * (signal / noise).log10()) as f32
+    };
+    ReconstructionError {
+        mse: (noise / n) as f32,
+        max_abs,
+        sqnr_db,
+        min_cosine: min_cosine as f32,
+        mean_cosine: (sum_cosine / rows.max(1) as f64) as f32,
+    }
+}
+
+/// Output error of the Monte-Carlo batch.
+pub struct MonteCarloError {
+    pub mean_mse: f32,
+    pub worst_row_mse: f32,
+    pub p99_abs: f32,
+}
+
+/// Multiply a batch of input vectors (`inputs`, `n x k` in original column order, or drawn
+/// from `config`) through the source weights and through `blocks`.
+pub fn monte_carlo_error<T: GgmlType>(
+    config: &MonteCarloConfig,
+    inputs: Option<Vec<f32>>,
+    blocks: &[T],
+    k: usize,
+    source: &SourceColumns<'_>,
+) -> Result<MonteCarloError> {
+    let rows = source.weights.len() / k;
+    let x = match inputs {
+        Some(x) if !x.is_empty() => x,
+        _ => {
+            let mut rng = StdRng::seed_from_u64(config.seed);
+            let n = config.samples * k;
+            match config.inputs {
+                MonteCarloInputs::HeavyTailed { dof } => {
+                    let dist = StudentT::new(dof).context("invalid degrees of freedom")?;
+                    (0..n).map(|_| dist.sample(&mut rng)).collect()
+                }
+                _ => (0..n).map(|_| StandardNormal.sample(&mut rng)).collect(),
+            }
+        }
+    };
+    let n = x.len() / k;
+    let gathered = gather_inputs(&x, k, source);
+    let mut quantized = vec![0f32; n * rows];
+    matmul::<T>((n, k, rows), &gathered, blocks, &mut quantized)
+        .map_err(|e| anyhow::anyhow!("monte-carlo validation matmul failed: {}", e))?;
+
+    let mut abs_errors = Vec::with_capacity(n * rows);
+    let mut row_sq = vec![0f64; rows];
+    for (b, xs) in x.chunks_exact(k).enumerate() {
+        for (r, sq) in row_sq.iter_mut().enumerate() {
+            let y_ref: f64 = source.weights[r * k..(r + 1) * k]
+                .iter()
+                .zip(xs)
+                .map(|(&w, &v)| w as f64 * v as f64)
+                .sum();
+            let e = quantized[b * rows + r] as f64 - y_ref;
+            *sq += e * e;
+            abs_errors.push(e.abs() as f32);
+        }
+    }
+    let total: f64 = row_sq.iter().sum();
+    let worst = row_sq.iter().cloned().fold(0f64, f64::max);
+    let p99_idx = ((abs_errors.len() as f64 * 0.99).ceil() as usize).clamp(1, abs_errors.len()) - 1;
+    let (_, p99, _) = abs_errors.select_nth_unstable_by(p99_idx, f32::total_cmp);
+    Ok(MonteCarloError {
+        mean_mse: (total / (n * rows) as f64) as f32,
+        worst_row_mse: (worst / n as f64) as f32,
+        p99_abs: *p99,
+    })
+}
+
+/// Gather `n x k` activations into quantized column order: `x'[i] = x[perm[i]] / scales[perm[i]]`.
+fn gather_inputs(x: &[f32], k: usize, source: &SourceColumns<'_>) -> Vec<f32> {
+    let mut gathered = vec![0f32; x.len()];
+    for (xs, gs) in x.chunks_exact(k).zip(gathered.chunks_exact_mut(k)) {
+        for (i, g) in gs.iter_mut().enumerate() {
+            let col = source.perm.map_or(i, |p| p[i]);
+            *g = xs[col] / source.scales.map_or(1.0, |s| s[col]);
+        }
+    }
+    gathered
+}
+
+pub fn validate_quantization<T: GgmlType>(original: &[f32], blocks: &[T], k: usize) -> Result<f32> {
     let rows = original.len() / k;
     let _device = Device::Cpu;
     let test_input = vec![1.0f32; k]; // Simple test vector
@@ -18,7 +754,7 @@ pub fn validate_quantization(original: &[f32], blocks: &[BlockQ8K], k: usize) ->
 
     // Actual output: multiply quantized weights by test vector
     let mut actual_output = vec![0f32; rows];
-    matmul::<BlockQ8K>((1, k, rows), &test_input, blocks, &mut actual_output)
+    matmul::<T>((1, k, rows), &test_input, blocks, &mut actual_output)
         .map_err(|e| anyhow::anyhow!("matmul failed: {}", e))?;
 
     // Calculate MSE between expected and actual outputs
@@ -32,17 +768,17 @@ pub fn validate_quantization(original: &[f32], blocks: &[BlockQ8K], k: usize) ->
     Ok(mse)
 }
 
-pub fn validate_quantization_direct(
+pub fn validate_quantization_direct<T: GgmlType>(
     original: &[f32],
-    blocks: &[BlockQ8K],
+    blocks: &[T],
     k: usize,
 ) -> Result<f32> {
     let rows = original.len() / k;
 
     // Use a different test pattern to validate reconstruction quality
     let mut test_input = vec![0f32; k];
-    for i in 0..k {
-        test_input[i] = (i as f32 + 1.0) / k as f32; // Gradient from 0 to 1
+    for (i, v) in test_input.iter_mut().enumerate() {
+        *v = (i as f32 + 1.0) / k as f32; // Gradient from 0 to 1
     }
 
     // Expected output: multiply original weights by test vector
@@ -58,7 +794,7 @@ pub fn validate_quantization_direct(
 
     // Actual output: multiply quantized weights by test vector
     let mut actual_output = vec![0f32; rows];
-    matmul::<BlockQ8K>((1, k, rows), &test_input, blocks, &mut actual_output)
+    matmul::<T>((1, k, rows), &test_input, blocks, &mut actual_output)
         .map_err(|e| anyhow::anyhow!("direct validation matmul failed: {}", e))?;
 
     // Calculate MSE between expected and actual outputs
@@ -0,0 +1,164 @@
+//! Re-validation of quantized output against its source model.
+//!
+//! Every `.q8k` file in an output directory is loaded with its `.perm` and `.scale`
+//! sidecars and run through the same metrics and quality gates as during quantization, so a
+//! directory can be checked again later, with other metrics or stricter thresholds.
+
+use super::io::{load_quantized, load_scales, read_q8k_header};
+use super::quality::{check_quality_gates, GateFailure, GateMode, QualityGateError};
+use super::validation::{
+    run_validation, MonteCarloInputs, SourceColumns, TensorMetrics, ValidationConfig,
+    ValidationMetric,
+};
+use super::{CalibrationInputs, ModelSource, QuantFormat, SourceTensor};
+use anyhow::{bail, Context, Result};
+use candle_core::quantized::k_quants::{
+    BlockQ2K, BlockQ3K, BlockQ4K, BlockQ5K, BlockQ6K, BlockQ8K,
+};
+use candle_core::quantized::GgmlType;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Metrics of every verified tensor, in model order.
+#[derive(Debug, Clone)]
+pub struct VerifyResult {
+    pub tensors: Vec<TensorMetrics>,
+    /// Breaches in `GateMode::Warn`; in `GateMode::Fail` they are returned as an error
+    pub gate_failures: Vec<GateFailure>,
+}
+
+/// Validate each `.q8k` file in `output_dir` against the tensor of the same name in
+/// `input_path`. Fails on files without a matching source tensor or with another shape.
+pub fn verify_quantized(
+    input_path: &Path,
+    output_dir: &Path,
+    config: &ValidationConfig,
+) -> Result<VerifyResult> {
+    if !output_dir.is_dir() {
+        bail!(
+            "{} is not a directory; verify reads per-tensor .q8k output",
+            output_dir.display()
+        );
+    }
+    let source = ModelSource::open(input_path)?;
+    let order: HashMap<&str, usize> = source
+        .tensors()
+        .iter()
+        .enumerate()
+        .map(|(idx, t)| (t.name.as_str(), idx))
+        .collect();
+
+    let mut files: Vec<(usize, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(output_dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|e| e != "q8k") {
+            continue;
+        }
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let Some(&idx) = order.get(name.as_ref()) else {
+            bail!(
+                "{}: no tensor {name:?} in {}",
+                path.display(),
+                input_path.display()
+            );
+        };
+        files.push((idx, path));
+    }
+    if files.is_empty() {
+        bail!("no .q8k files in {}", output_dir.display());
+    }
+    files.sort();
+
+    let calibration = match config.monte_carlo.inputs {
+        MonteCarloInputs::Calibration(ref path) if config.runs(ValidationMetric::MonteCarlo) => {
+            Some(CalibrationInputs::load(path)?)
+        }
+        _ => None,
+    };
+    let mut tensors = Vec::with_capacity(files.len());
+    for (idx, path) in files {
+        let tensor = &source.tensors()[idx];
+        let hdr = read_q8k_header(&path)?;
+        let format = QuantFormat::from_dtype_id(hdr.dtype)
+            .with_context(|| format!("unknown dtype {:#x} in {}", hdr.dtype, path.display()))?;
+        println!("verifying {} ({})", tensor.name, format.name());
+        let verify = match format {
+            QuantFormat::Q8K => verify_tensor::<BlockQ8K>,
+            QuantFormat::Q6K => verify_tensor::<BlockQ6K>,
+            QuantFormat::Q5K => verify_tensor::<BlockQ5K>,
+            QuantFormat::Q4K => verify_tensor::<BlockQ4K>,
+            QuantFormat::Q3K => verify_tensor::<BlockQ3K>,
+            QuantFormat::Q2K => verify_tensor::<BlockQ2K>,
+        };
+        let metrics = verify(config, &source, tensor, &path, calibration.as_ref())?;
+        if metrics.validated_rows > 0 {
+            println!("  {}", metrics.summary());
+        }
+        tensors.push(metrics);
+    }
+
+    let gate_failures = check_quality_gates(config, tensors.iter());
+    for failure in &gate_failures {
+        println!("  [WARN] {failure}");
+    }
+    if config.gate == GateMode::Fail && !gate_failures.is_empty() {
+        return Err(QualityGateError {
+            failures: gate_failures,
+        }
+        .into());
+    }
+    Ok(VerifyResult {
+        tensors,
+        gate_failures,
+    })
+}
+
+fn verify_tensor<T: GgmlType>(
+    config: &ValidationConfig,
+    source: &ModelSource,
+    tensor: &SourceTensor,
+    path: &Path,
+    calibration: Option<&CalibrationInputs>,
+) -> Result<TensorMetrics> {
+    let (blocks, rows, k, perm) = load_quantized::<T>(path)?;
+    let scales = load_scales(path)?;
+    if tensor.shape != [rows, k] {
+        bail!(
+            "{}: holds {rows} x {k}, source tensor {} is {:?}",
+            path.display(),
+            tensor.name,
+            tensor.shape
+        );
+    }
+    let weights = source.tensor_f32(tensor)?;
+    source.release(tensor);
+
+    // The weights as they were quantized: column i is source column perm[i] times its scale
+    let quantized_from = if perm.is_some() || scales.is_some() {
+        let mut data = Vec::with_capacity(weights.len());
+        for row in weights.chunks_exact(k) {
+            data.extend((0..k).map(|i| {
+                let col = perm.as_ref().map_or(i, |p| p[i]);
+                row[col] * scales.as_ref().map_or(1.0, |s| s[col])
+            }));
+        }
+        data
+    } else {
+        weights.clone()
+    };
+    let columns = SourceColumns {
+        weights: &weights,
+        perm: perm.as_deref(),
+        scales: scales.as_deref(),
+    };
+    run_validation(
+        config,
+        &tensor.name,
+        &quantized_from,
+        &blocks,
+        k,
+        Some(&columns),
+        calibration,
+    )
+}
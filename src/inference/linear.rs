@@ -0,0 +1,227 @@
+//! A quantized linear layer over permuted, optionally scaled columns.
+//!
+//! The stored weight has column `i` equal to source column `perm[i]` times
+//! `scales[perm[i]]`, so `W x` is computed as the quantized matmul of the stored weight with
+//! `x[perm[i]] / scales[perm[i]]`. The gather and the division run on the weight's device.
+//!
+//! candle has no quantized matmul kernel for Q8_K on CUDA or Metal, so a Q8_K weight on a
+//! GPU is dequantized to f32 when the layer is built and multiplied densely; the other
+//! k-quant formats stay quantized on every device.
+
+use crate::core::io::{load_perm, load_scales, read_q8k_header, MappedQuantized};
+use crate::core::QuantFormat;
+use anyhow::{bail, Context, Result};
+use candle_core::quantized::k_quants::{
+    BlockQ2K, BlockQ3K, BlockQ4K, BlockQ5K, BlockQ6K, BlockQ8K,
+};
+use candle_core::quantized::{GgmlDType, GgmlType, QMatMul, QStorage, QTensor};
+use candle_core::{DType, Device, Module, Tensor, D};
+use std::borrow::Cow;
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct PermutedQLinear {
+    matmul: QMatMul,
+    /// Source column of each stored column (`u32`); `None` when the columns are in place
+    perm: Option<Tensor>,
+    /// `1 / scales[perm[i]]` for stored column `i`
+    inv_scales: Option<Tensor>,
+    bias: Option<Tensor>,
+    in_dim: usize,
+    out_dim: usize,
+}
+
+impl PermutedQLinear {
+    /// Wrap a quantized `[out, in]` weight with the permutation and column scales it was
+    /// quantized with. Both are in the sidecar layout: `perm[i]` is the source column of
+    /// stored column `i`, `scales` are indexed by source column.
+    pub fn new(weight: QTensor, perm: Option<&[usize]>, scales: Option<&[f32]>) -> Result<Self> {
+        let &[out_dim, in_dim] = weight.shape().dims() else {
+            bail!("expected a 2-D weight, got {:?}", weight.shape());
+        };
+        let device = weight.device();
+        if let Some(perm) = perm {
+            let mut seen = vec![false; in_dim];
+            if perm.len() != in_dim
+                || !perm
+                    .iter()
+                    .all(|&p| p < in_dim && !std::mem::replace(&mut seen[p], true))
+            {
+                bail!("permutation is not a bijection on 0..{in_dim}");
+            }
+        }
+        let inv_scales = match scales {
+            Some(scales) if scales.len() != in_dim => {
+                bail!("{} scales for {in_dim} columns", scales.len())
+            }
+            Some(scales) => {
+                if let Some(s) = scales.iter().find(|s| !(s.is_finite() && **s > 0.0)) {
+                    bail!("scale {s} is not positive");
+                }
+                let inv: Vec<f32> = (0..in_dim)
+                    .map(|i| 1.0 / scales[perm.map_or(i, |p| p[i])])
+                    .collect();
+                Some(Tensor::from_vec(inv, in_dim, &device)?)
+            }
+            None => None,
+        };
+        let perm = perm
+            .map(|p| {
+                let idx: Vec<u32> = p.iter().map(|&p| p as u32).collect();
+                Tensor::from_vec(idx, in_dim, &device)
+            })
+            .transpose()?;
+        let matmul = if weight.dtype() == GgmlDType::Q8K && !device.is_cpu() {
+            QMatMul::Tensor(weight.dequantize(&device)?)
+        } else {
+            QMatMul::from_qtensor(weight)?
+        };
+        Ok(Self {
+            matmul,
+            perm,
+            inv_scales,
+            bias: None,
+            in_dim,
+            out_dim,
+        })
+    }
+
+    /// Load a `.q8k` file of any k-quant format onto `device`, with its permutation (from the
+    /// `.perm` sidecar or the header) and `.scale` sidecar if present.
+    pub fn load(path: &Path, device: &Device) -> Result<Self> {
+        Self::new(
+            load_qtensor(path, device)?,
+            load_perm(path)?.as_deref(),
+            load_scales(path)?.as_deref(),
+        )
+        .with_context(|| format!("loading {}", path.display()))
+    }
+
+    /// Add `bias` (`[out]`) to the output.
+    pub fn with_bias(mut self, bias: Tensor) -> Result<Self> {
+        if bias.dims() != [self.out_dim] {
+            bail!(
+                "bias of shape {:?} for {} outputs",
+                bias.shape(),
+                self.out_dim
+            );
+        }
+        self.bias = Some(bias);
+        Ok(self)
+    }
+
+    pub fn in_dim(&self) -> usize {
+        self.in_dim
+    }
+
+    pub fn out_dim(&self) -> usize {
+        self.out_dim
+    }
+
+    pub fn is_permuted(&self) -> bool {
+        self.perm.is_some()
+    }
+}
+
+impl Module for PermutedQLinear {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        let mut xs = match self.perm {
+            Some(ref perm) => xs.index_select(perm, D::Minus1)?,
+            None => xs.clone(),
+        };
+        if let Some(ref inv) = self.inv_scales {
+            xs = match xs.dtype() {
+                DType::F32 => xs.broadcast_mul(inv)?,
+                dtype => xs.broadcast_mul(&inv.to_dtype(dtype)?)?,
+            };
+        }
+        let ys = self.matmul.forward(&xs)?;
+        match self.bias {
+            Some(ref bias) => ys.broadcast_add(&bias.to_dtype(ys.dtype())?),
+            None => Ok(ys),
+        }
+    }
+}
+
+/// The blocks of a `.q8k` file as a `[rows, k]` tensor on `device`.
+pub(crate) fn load_qtensor(path: &Path, device: &Device) -> Result<QTensor> {
+    let hdr = read_q8k_header(path)?;
+    let format = QuantFormat::from_dtype_id(hdr.dtype)
+        .with_context(|| format!("unknown dtype {:#x} in {}", hdr.dtype, path.display()))?;
+    match format {
+        QuantFormat::Q8K => blocks_to_qtensor::<BlockQ8K>(path, device),
+        QuantFormat::Q6K => blocks_to_qtensor::<BlockQ6K>(path, device),
+        QuantFormat::Q5K => blocks_to_qtensor::<BlockQ5K>(path, device),
+        QuantFormat::Q4K => blocks_to_qtensor::<BlockQ4K>(path, device),
+        QuantFormat::Q3K => blocks_to_qtensor::<BlockQ3K>(path, device),
+        QuantFormat::Q2K => blocks_to_qtensor::<BlockQ2K>(path, device),
+    }
+}
+
+fn blocks_to_qtensor<T: GgmlType>(path: &Path, device: &Device) -> Result<QTensor> {
+    let mapped = MappedQuantized::<T>::open(path)?;
+    mapped.verify_checksum()?;
+    let storage = QStorage::from_data(Cow::Borrowed(mapped.bytes()), device, T::DTYPE)?;
+    Ok(QTensor::new(storage, (mapped.rows(), mapped.k()))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategies::quantize_rows;
+
+    #[test]
+    fn forward_matches_dense_matmul_on_permuted_scaled_weight() -> Result<()> {
+        let (out, k) = (8, 512);
+        let device = Device::Cpu;
+        let weight: Vec<f32> = (0..out * k)
+            .map(|i| ((i * 7919 % 1013) as f32 / 1013.0 - 0.5) * 0.2)
+            .collect();
+        let perm: Vec<usize> = (0..k).map(|i| (i * 37 + 11) % k).collect();
+        let scales: Vec<f32> = (0..k).map(|c| 0.5 + (c % 5) as f32 * 0.25).collect();
+        let mut stored = vec![0f32; out * k];
+        for r in 0..out {
+            for (i, &p) in perm.iter().enumerate() {
+                stored[r * k + i] = weight[r * k + p] * scales[p];
+            }
+        }
+        let blocks = quantize_rows::<BlockQ8K>(out, k, &stored)?;
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                blocks.as_ptr() as *const u8,
+                std::mem::size_of_val(blocks.as_slice()),
+            )
+        };
+        let storage = QStorage::from_data(Cow::Borrowed(bytes), &device, GgmlDType::Q8K)?;
+        let qtensor = QTensor::new(storage, (out, k))?;
+        let linear = PermutedQLinear::new(qtensor, Some(&perm), Some(&scales))?;
+
+        // Reference: the dequantized stored weight with its columns put back in place
+        let mut restored = vec![0f32; out * k];
+        BlockQ8K::to_float(&blocks, &mut restored);
+        let mut dense = vec![0f32; out * k];
+        for r in 0..out {
+            for (i, &p) in perm.iter().enumerate() {
+                dense[r * k + p] = restored[r * k + i] / scales[p];
+            }
+        }
+        let xs: Vec<f32> = (0..3 * k)
+            .map(|i| ((i * 31 % 17) as f32 - 8.0) / 8.0)
+            .collect();
+        let xs = Tensor::from_vec(xs, (3, k), &device)?;
+        let dense = Tensor::from_vec(dense, (out, k), &device)?;
+        let expected = xs.matmul(&dense.t()?)?;
+        let actual = linear.forward(&xs)?;
+        let err = (&actual - &expected)?
+            .sqr()?
+            .sum_all()?
+            .to_scalar::<f32>()?;
+        let norm = expected.sqr()?.sum_all()?.to_scalar::<f32>()?;
+        assert!(
+            (err / norm).sqrt() < 1e-2,
+            "relative error {}",
+            (err / norm).sqrt()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,359 @@
+//! Tensor lookup over a whole quantized output, in the manner of candle's quantized
+//! `VarBuilder`.
+//!
+//! The loader indexes a directory of `.q8k` files or a single GGUF file up front (names,
+//! shapes and types only) and reads a tensor when it is asked for. Prefixes are pushed with
+//! `pp`, so model code can hand a sub-loader to each layer. Tensors that per-tensor output
+//! leaves in the source model (norms, embeddings) are found there when a source is added.
+
+use super::linear::{load_qtensor, PermutedQLinear};
+use crate::core::io::read_q8k_header;
+use crate::core::{ModelSource, QuantFormat, REPORT_CSV, REPORT_JSON};
+use anyhow::{bail, Context, Result};
+use candle_core::quantized::{GgmlDType, QTensor};
+use candle_core::{Device, Shape};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Where an indexed tensor is read from.
+#[derive(Debug, Clone)]
+enum Location {
+    /// A `.q8k` file with optional `.perm` / `.scale` sidecars
+    File(PathBuf),
+    /// Index into the tensors of the GGUF container
+    Container(usize),
+    /// Index into the tensors of the source model
+    Source(usize),
+}
+
+#[derive(Debug, Clone)]
+struct IndexedTensor {
+    shape: Vec<usize>,
+    dtype: Option<GgmlDType>,
+    location: Location,
+}
+
+struct Index {
+    root: PathBuf,
+    device: Device,
+    tensors: BTreeMap<String, IndexedTensor>,
+    container: Option<ModelSource>,
+    source: Option<ModelSource>,
+    /// Names handed out so far, for `unused`
+    loaded: Mutex<HashSet<String>>,
+}
+
+/// Expected tensor names compared with what the output holds.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TensorCoverage {
+    /// Expected but not in the output
+    pub missing: Vec<String>,
+    /// In the output but not expected
+    pub extra: Vec<String>,
+}
+
+impl TensorCoverage {
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+impl fmt::Display for TensorCoverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} missing tensor(s)", self.missing.len())?;
+        for name in &self.missing {
+            writeln!(f, "  - {name}")?;
+        }
+        writeln!(f, "{} extra tensor(s)", self.extra.len())?;
+        for name in &self.extra {
+            writeln!(f, "  + {name}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Cheap to clone; clones and `pp` sub-loaders share the index.
+#[derive(Clone)]
+pub struct QuantizedModelLoader {
+    index: Arc<Index>,
+    path: Vec<String>,
+}
+
+impl QuantizedModelLoader {
+    /// Index `path`: a per-tensor output directory, a GGUF file, or a directory holding only
+    /// a GGUF file (`--gguf` output). Reports next to the tensors are ignored.
+    pub fn open(path: &Path, device: &Device) -> Result<Self> {
+        let mut tensors = BTreeMap::new();
+        let mut container = None;
+        if path.is_dir() {
+            let mut ggufs = Vec::new();
+            for entry in fs::read_dir(path)? {
+                let file = entry?.path();
+                let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+                if file_name == REPORT_JSON || file_name == REPORT_CSV {
+                    continue;
+                }
+                match file.extension().and_then(|e| e.to_str()) {
+                    Some("q8k") => {
+                        let hdr = read_q8k_header(&file)?;
+                        let format = QuantFormat::from_dtype_id(hdr.dtype).with_context(|| {
+                            format!("unknown dtype {:#x} in {}", hdr.dtype, file.display())
+                        })?;
+                        let name = file.file_stem().unwrap_or_default().to_string_lossy();
+                        tensors.insert(
+                            name.into_owned(),
+                            IndexedTensor {
+                                shape: vec![hdr.out as usize, hdr.k as usize],
+                                dtype: Some(format.ggml_dtype()),
+                                location: Location::File(file),
+                            },
+                        );
+                    }
+                    Some("gguf") => ggufs.push(file),
+                    _ => {}
+                }
+            }
+            match (tensors.is_empty(), ggufs.as_slice()) {
+                (false, []) => {}
+                (true, [gguf]) => container = Some(open_container(gguf)?),
+                (true, []) => bail!("no .q8k or .gguf files in {}", path.display()),
+                (true, _) => bail!("{} holds several GGUF files", path.display()),
+                (false, _) => bail!("{} holds both .q8k and GGUF output", path.display()),
+            }
+        } else {
+            container = Some(open_container(path)?);
+        }
+        if let Some(ref container) = container {
+            for (idx, tensor) in container.tensors().iter().enumerate() {
+                tensors.insert(
+                    tensor.name.clone(),
+                    IndexedTensor {
+                        shape: tensor.shape.clone(),
+                        dtype: tensor.dtype,
+                        location: Location::Container(idx),
+                    },
+                );
+            }
+        }
+        Ok(Self {
+            index: Arc::new(Index {
+                root: path.to_path_buf(),
+                device: device.clone(),
+                tensors,
+                container,
+                source: None,
+                loaded: Mutex::new(HashSet::new()),
+            }),
+            path: Vec::new(),
+        })
+    }
+
+    /// Also serve the tensors of `source` that the output does not hold, i.e. those left
+    /// unquantized. Call before any sub-loader is made.
+    pub fn with_source(mut self, source: &Path) -> Result<Self> {
+        let Some(index) = Arc::get_mut(&mut self.index) else {
+            bail!("with_source must be called before the loader is shared");
+        };
+        let model = ModelSource::open(source)?;
+        for (idx, tensor) in model.tensors().iter().enumerate() {
+            match index.tensors.get(&tensor.name) {
+                Some(t) if t.shape != tensor.shape => bail!(
+                    "{} is {:?} in the quantized output but {:?} in {}",
+                    tensor.name,
+                    t.shape,
+                    tensor.shape,
+                    source.display()
+                ),
+                Some(_) => {}
+                None => {
+                    index.tensors.insert(
+                        tensor.name.clone(),
+                        IndexedTensor {
+                            shape: tensor.shape.clone(),
+                            dtype: tensor.dtype,
+                            location: Location::Source(idx),
+                        },
+                    );
+                }
+            }
+        }
+        index.source = Some(model);
+        Ok(self)
+    }
+
+    /// A loader whose names are prefixed with `s`, as in `VarBuilder::pp`.
+    pub fn pp<S: ToString>(&self, s: S) -> Self {
+        let mut path = self.path.clone();
+        path.push(s.to_string());
+        Self {
+            index: self.index.clone(),
+            path,
+        }
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.index.device
+    }
+
+    fn full_name(&self, name: &str) -> String {
+        if self.path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{name}", self.path.join("."))
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<(String, &IndexedTensor)> {
+        let full = self.full_name(name);
+        match self.index.tensors.get(&full) {
+            Some(t) => Ok((full, t)),
+            None => bail!("no tensor {full} in {}", self.index.root.display()),
+        }
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.index.tensors.contains_key(&self.full_name(name))
+    }
+
+    /// Shape of `name` without loading it.
+    pub fn shape(&self, name: &str) -> Result<&[usize]> {
+        Ok(&self.lookup(name)?.1.shape)
+    }
+
+    /// Storage type of `name`; `None` for source tensors without a ggml equivalent.
+    pub fn dtype(&self, name: &str) -> Result<Option<GgmlDType>> {
+        Ok(self.lookup(name)?.1.dtype)
+    }
+
+    /// Tensor `name` as stored, checked against `shape`. Tensors with permuted or scaled
+    /// columns are refused, since a plain matmul over them is wrong; use `get_linear`.
+    pub fn get<S: Into<Shape>>(&self, shape: S, name: &str) -> Result<Arc<QTensor>> {
+        let shape = shape.into();
+        let (full, tensor) = self.lookup(name)?;
+        if shape.dims() != tensor.shape {
+            bail!(
+                "shape mismatch for {full}, got {:?}, expected {:?}",
+                tensor.shape,
+                shape.dims()
+            );
+        }
+        self.get_no_shape(name)
+    }
+
+    /// Tensor `name` as stored, whatever its shape.
+    pub fn get_no_shape(&self, name: &str) -> Result<Arc<QTensor>> {
+        let (full, tensor) = self.lookup(name)?;
+        let index = &self.index;
+        let qtensor = match tensor.location {
+            Location::File(ref path) => {
+                if path.with_extension("perm").exists()
+                    || path.with_extension("scale").exists()
+                    || read_q8k_header(path)?.has_embedded_perm()
+                {
+                    bail!("{full} has permuted or scaled columns; load it with get_linear");
+                }
+                load_qtensor(path, &index.device)?
+            }
+            Location::Container(idx) => {
+                let container = index.container.as_ref().unwrap();
+                if container.column_perm(&full)?.is_some()
+                    || container.column_scales(&full)?.is_some()
+                {
+                    bail!("{full} has permuted or scaled columns; load it with get_linear");
+                }
+                container.qtensor(&container.tensors()[idx], &index.device)?
+            }
+            Location::Source(idx) => {
+                let source = index.source.as_ref().unwrap();
+                source.qtensor(&source.tensors()[idx], &index.device)?
+            }
+        };
+        index.loaded.lock().unwrap().insert(full);
+        Ok(Arc::new(qtensor))
+    }
+
+    /// Tensor `name` (`[out, in]`) as a linear layer that undoes its column permutation and
+    /// scaling on the input.
+    pub fn get_linear(&self, name: &str) -> Result<PermutedQLinear> {
+        let (full, tensor) = self.lookup(name)?;
+        let index = &self.index;
+        let linear = match tensor.location {
+            Location::File(ref path) => PermutedQLinear::load(path, &index.device)?,
+            Location::Container(idx) => {
+                let container = index.container.as_ref().unwrap();
+                PermutedQLinear::new(
+                    container.qtensor(&container.tensors()[idx], &index.device)?,
+                    container.column_perm(&full)?.as_deref(),
+                    container.column_scales(&full)?.as_deref(),
+                )
+                .with_context(|| format!("loading {full}"))?
+            }
+            Location::Source(idx) => {
+                let source = index.source.as_ref().unwrap();
+                PermutedQLinear::new(
+                    source.qtensor(&source.tensors()[idx], &index.device)?,
+                    None,
+                    None,
+                )
+                .with_context(|| format!("loading {full}"))?
+            }
+        };
+        index.loaded.lock().unwrap().insert(full);
+        Ok(linear)
+    }
+
+    /// Every indexed tensor name, sorted.
+    pub fn tensor_names(&self) -> impl Iterator<Item = &str> {
+        self.index.tensors.keys().map(String::as_str)
+    }
+
+    /// Compare the indexed tensors with the names a model expects (full names, ignoring
+    /// this loader's prefix).
+    pub fn coverage<I, S>(&self, expected: I) -> TensorCoverage
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let expected: HashSet<String> = expected
+            .into_iter()
+            .map(|s| s.as_ref().to_string())
+            .collect();
+        let mut missing: Vec<String> = expected
+            .iter()
+            .filter(|name| !self.index.tensors.contains_key(*name))
+            .cloned()
+            .collect();
+        missing.sort();
+        let extra = self
+            .tensor_names()
+            .filter(|name| !expected.contains(*name))
+            .map(str::to_string)
+            .collect();
+        TensorCoverage { missing, extra }
+    }
+
+    /// Indexed tensors that nothing has loaded yet; after building a model these are the
+    /// ones it does not use.
+    pub fn unused(&self) -> Vec<String> {
+        let loaded = self.index.loaded.lock().unwrap();
+        self.tensor_names()
+            .filter(|name| !loaded.contains(*name))
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+fn open_container(path: &Path) -> Result<ModelSource> {
+    let container = ModelSource::open(path)?;
+    if !container.is_gguf() {
+        bail!(
+            "{} is neither an output directory nor a GGUF file",
+            path.display()
+        );
+    }
+    Ok(container)
+}
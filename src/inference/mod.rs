@@ -0,0 +1,24 @@
+//! Running quantized output with candle.
+
+pub mod linear;
+pub mod loader;
+
+pub use linear::PermutedQLinear;
+pub use loader::{QuantizedModelLoader, TensorCoverage};
+
+use anyhow::Result;
+use candle_core::Device;
+
+/// The first CUDA or Metal device when the crate is built with the `cuda` / `metal` feature
+/// and one is present, the CPU otherwise.
+pub fn preferred_device() -> Result<Device> {
+    #[cfg(feature = "cuda")]
+    if candle_core::utils::cuda_is_available() {
+        return Ok(Device::new_cuda(0)?);
+    }
+    #[cfg(feature = "metal")]
+    if candle_core::utils::metal_is_available() {
+        return Ok(Device::new_metal(0)?);
+    }
+    Ok(Device::Cpu)
+}
@@ -1,23 +1,23 @@
 // //! Advanced quantization strategies for neural network models.
 
 pub mod core;
+pub mod inference;
 pub mod strategies;
 pub mod utils;
 
-// Import for cleaner type references
-use candle_core::quantized::k_quants::BlockQ8K;
-
 // Re-export commonly used types
 pub use core::{
-    QuantizationConfig, QuantizationResult, Q8KHeader, 
-    MAGIC_Q8K, VERSION, DTYPE_Q8K
+    QuantizationConfig, QuantizationResult, GptqConfig, OutputLayout, Q8KHeader, Q8KHeaderV2, QuantFormat,
+    GateMode, MonteCarloConfig, QualityGateError, TensorMetrics, ValidationConfig, ValidationMetric, MAGIC_Q8K, VERSION, DTYPE_Q8K
 };
 
 pub use strategies::{
     QuantizationStrategy, StrategyType,
-    L2NormStrategy, AttentionAwareStrategy
+    L2NormStrategy, AttentionAwareStrategy, AutoStrategy
 };
 
+pub use inference::{PermutedQLinear, QuantizedModelLoader};
+
 pub use utils::{
     tensor_to_f32, apply_column_permutation, 
     column_l2_norms, build_column_permutation
@@ -26,7 +26,7 @@ pub use utils::{
 use anyhow::Result;
 use std::path::Path;
 
-/// High-level API for quantizing safetensors files
+/// High-level API for quantizing safetensors files (single, sharded via index or directory) or GGUF
 pub fn quantize_safetensors(
     input_path: &Path,
     config: QuantizationConfig,
@@ -35,8 +35,11 @@ pub fn quantize_safetensors(
 }
 
 /// Load a quantized .q8k tensor for inference
-pub fn load_quantized_tensor(
-    path: &Path,
-) -> Result<(Vec<BlockQ8K>, usize, usize, Option<Vec<usize>>)> {
+pub fn load_quantized_tensor(path: &Path) -> Result<core::Q8KTensor> {
     core::io::load_q8k_tensor(path)
+}
+
+/// Map a quantized .q8k tensor and borrow its blocks without copying them
+pub fn map_quantized_tensor(path: &Path) -> Result<core::MappedQ8K> {
+    core::io::map_q8k_tensor(path)
 }
\ No newline at end of file
@@ -0,0 +1,173 @@
+//! Activation-aware (AWQ-style) column scaling and permutation.
+//!
+//! Columns are scaled by `s_j = a_j^alpha`, where `a_j` is the calibration activation
+//! magnitude of input channel `j`, so salient channels get a finer share of each block scale.
+//! The columns are then ordered by their scaled L2 norm. `alpha` is picked per tensor from a
+//! small grid by the activation-weighted reconstruction error after rounding to the output
+//! format.
+
+use super::{format_round_trip, QuantizationStrategy};
+use crate::core::{ActivationStats, QuantFormat};
+use crate::utils::{apply_column_permutation, build_column_permutation, column_l2_norms};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Number of grid steps for `alpha` in `[0, 1]`.
+const ALPHA_GRID: usize = 10;
+/// Lower bound on activation magnitudes so dead channels keep a usable scale.
+const MIN_ACTIVATION: f32 = 1e-5;
+
+/// Best scaling found so far during the `alpha` search.
+struct Candidate {
+    alpha: f32,
+    err: f64,
+    scales: Vec<f32>,
+    perm: Vec<usize>,
+    permuted: Vec<f32>,
+}
+
+pub struct ActivationAwareStrategy {
+    stats: ActivationStats,
+    format: QuantFormat,
+    scales: Mutex<HashMap<String, Vec<f32>>>,
+}
+
+impl ActivationAwareStrategy {
+    /// Strategy for output in `format`, whose rounding scores each `alpha`.
+    pub fn new(stats: ActivationStats, format: QuantFormat) -> Self {
+        Self {
+            stats,
+            format,
+            scales: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_file(calibration_path: &Path, format: QuantFormat) -> Result<Self> {
+        let stats = ActivationStats::load(calibration_path)?;
+        println!(
+            "Calibration: {} activation statistics from {}",
+            stats.len(),
+            calibration_path.display()
+        );
+        Ok(Self::new(stats, format))
+    }
+
+    /// AWQ scales `a^alpha`, normalised so their geometric range is centred on one.
+    fn channel_scales(activations: &[f32], alpha: f32) -> Vec<f32> {
+        let raw: Vec<f32> = activations
+            .iter()
+            .map(|&a| a.abs().max(MIN_ACTIVATION).powf(alpha))
+            .collect();
+        let max = raw.iter().cloned().fold(f32::MIN_POSITIVE, f32::max);
+        let min = raw.iter().cloned().fold(f32::MAX, f32::min);
+        let norm = (max * min).sqrt();
+        raw.into_iter().map(|s| s / norm).collect()
+    }
+}
+
+impl QuantizationStrategy for ActivationAwareStrategy {
+    fn apply_permutation(
+        &self,
+        data: &[f32],
+        rows: usize,
+        k: usize,
+        tensor_name: &str,
+    ) -> Result<(Vec<f32>, Option<Vec<usize>>)> {
+        let Some(activations) = self
+            .stats
+            .channel_means(tensor_name)
+            .or_else(|| self.stats.channel_maxes(tensor_name))
+        else {
+            // No calibration data for this tensor: use L2 norm strategy
+            let norms = column_l2_norms(rows, k, data);
+            let perm = build_column_permutation(&norms);
+            let permuted = apply_column_permutation(rows, k, data, &perm);
+            return Ok((permuted, Some(perm)));
+        };
+        if activations.len() != k {
+            bail!(
+                "activation stats for {} have {} channels, expected {}",
+                tensor_name,
+                activations.len(),
+                k
+            );
+        }
+
+        let weights: Vec<f64> = activations
+            .iter()
+            .map(|&a| {
+                let a = a.abs().max(MIN_ACTIVATION) as f64;
+                a * a
+            })
+            .collect();
+
+        let mut best: Option<Candidate> = None;
+        for step in 0..=ALPHA_GRID {
+            let alpha = step as f32 / ALPHA_GRID as f32;
+            let scales = Self::channel_scales(activations, alpha);
+
+            let mut scaled = data.to_vec();
+            for row in scaled.chunks_exact_mut(k) {
+                for (v, &s) in row.iter_mut().zip(scales.iter()) {
+                    *v *= s;
+                }
+            }
+            let norms = column_l2_norms(rows, k, &scaled);
+            let perm = build_column_permutation(&norms);
+            let permuted = apply_column_permutation(rows, k, &scaled, &perm);
+            let restored = format_round_trip(self.format, rows, k, &permuted)?;
+
+            // Error of W x in the original space, weighted by E[x_j^2].
+            let mut err = 0f64;
+            for r in 0..rows {
+                for (pos, &col) in perm.iter().enumerate() {
+                    let idx = r * k + pos;
+                    let d = ((permuted[idx] - restored[idx]) / scales[col]) as f64;
+                    err += weights[col] * d * d;
+                }
+            }
+
+            if best.as_ref().is_none_or(|b| err < b.err) {
+                best = Some(Candidate {
+                    alpha,
+                    err,
+                    scales,
+                    perm,
+                    permuted,
+                });
+            }
+        }
+        let Candidate {
+            alpha,
+            scales,
+            perm,
+            permuted,
+            ..
+        } = best.expect("alpha grid is non-empty");
+
+        if tensor_name.contains("layers.0.") {
+            println!(
+                "  Activation-aware scaling applied to {}: alpha = {:.1}",
+                tensor_name, alpha
+            );
+        }
+        if alpha > 0.0 {
+            self.scales
+                .lock()
+                .unwrap()
+                .insert(tensor_name.to_string(), scales);
+        }
+
+        Ok((permuted, Some(perm)))
+    }
+
+    fn take_column_scales(&self, tensor_name: &str) -> Option<Vec<f32>> {
+        self.scales.lock().unwrap().remove(tensor_name)
+    }
+
+    fn name(&self) -> &'static str {
+        "ActivationAware"
+    }
+}
@@ -6,11 +6,13 @@ use regex::Regex;
 use std::collections::HashMap;
 use std::sync::Mutex;
 
-static LAYER_PERM_CACHE: Lazy<std::sync::OnceLock<Mutex<LayerPermCache>>> =
-    Lazy::new(|| std::sync::OnceLock::new());
-
-static ATTN_PROJ_RE: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"^model\.layers\.(\d+)\.self_attn\.(q|k|v|o)_proj\.weight$").unwrap());
+// Hugging Face (`model.layers.N.self_attn.q_proj`) and GGUF (`blk.N.attn_q`) names.
+static ATTN_PROJ_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^(?:model\.layers\.(\d+)\.self_attn\.(q|k|v|o)_proj|blk\.(\d+)\.attn_(q|k|v|output))\.weight$",
+    )
+    .unwrap()
+});
 
 #[derive(Debug, Clone)]
 struct LayerPermCache {
@@ -23,33 +25,51 @@ impl LayerPermCache {
             map: HashMap::new(),
         }
     }
+}
 
-    fn get_or_compute(&mut self, layer_id: u32, rows: usize, k: usize, data: &[f32]) -> Vec<usize> {
-        if let Some(p) = self.map.get(&layer_id) {
-            return p.clone();
-        }
-        let norms = column_l2_norms(rows, k, data);
-        let perm = build_column_permutation(&norms);
-        self.map.insert(layer_id, perm.clone());
-        perm
-    }
+/// Shares one column permutation between the q/k/v projections of a layer; it is computed
+/// from the first of them to arrive, which `run_quantization` keeps in model order.
+pub struct AttentionAwareStrategy {
+    layer_perms: Mutex<LayerPermCache>,
 }
 
-pub struct AttentionAwareStrategy;
+impl Default for AttentionAwareStrategy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl AttentionAwareStrategy {
     pub fn new() -> Self {
-        Self
+        Self {
+            layer_perms: Mutex::new(LayerPermCache::new()),
+        }
+    }
+
+    fn layer_perm(&self, layer_id: u32, rows: usize, k: usize, data: &[f32]) -> Vec<usize> {
+        if let Some(p) = self.layer_perms.lock().unwrap().map.get(&layer_id) {
+            return p.clone();
+        }
+        // Computed without holding the lock so other layers are not serialised behind it.
+        let norms = column_l2_norms(rows, k, data);
+        let perm = build_column_permutation(&norms);
+        self.layer_perms
+            .lock()
+            .unwrap()
+            .map
+            .entry(layer_id)
+            .or_insert(perm)
+            .clone()
     }
 
     fn parse_attention_proj(name: &str) -> Option<(u32, &'static str)> {
         if let Some(caps) = ATTN_PROJ_RE.captures(name) {
-            let layer_id: u32 = caps[1].parse().ok()?;
-            let kind = match &caps[2] {
+            let layer_id: u32 = caps.get(1).or(caps.get(3))?.as_str().parse().ok()?;
+            let kind = match caps.get(2).or(caps.get(4))?.as_str() {
                 "q" => "q",
                 "k" => "k",
                 "v" => "v",
-                "o" => "o",
+                "o" | "output" => "o",
                 _ => return None,
             };
             Some((layer_id, kind))
@@ -73,13 +93,7 @@ impl QuantizationStrategy for AttentionAwareStrategy {
                 Ok((data.to_vec(), None))
             } else {
                 // Share permutation across q/k/v in same layer
-                let perm = {
-                    let mut cache = LAYER_PERM_CACHE
-                        .get_or_init(|| Mutex::new(LayerPermCache::new()))
-                        .lock()
-                        .unwrap();
-                    cache.get_or_compute(layer_id, rows, k, data)
-                };
+                let perm = self.layer_perm(layer_id, rows, k, data);
                 let permuted = apply_column_permutation(rows, k, data, &perm);
                 Ok((permuted, Some(perm)))
             }
@@ -92,6 +106,13 @@ impl QuantizationStrategy for AttentionAwareStrategy {
         }
     }
 
+    fn shared_state_key(&self, tensor_name: &str) -> Option<String> {
+        match Self::parse_attention_proj(tensor_name) {
+            Some((layer_id, "q" | "k" | "v")) => Some(format!("attn.{layer_id}")),
+            _ => None,
+        }
+    }
+
     fn name(&self) -> &'static str {
         "AttentionAware"
     }
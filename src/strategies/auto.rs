@@ -0,0 +1,159 @@
+//! Automatic per-tensor strategy selection.
+//!
+//! Every candidate strategy is applied to the tensor and its result rounded to the output
+//! format; the ordering with the lowest weight error in the original column space wins.
+//! Candidates are tried in order; once the per-tensor time budget has run out no new one is
+//! started, so cheap ones (identity, L2 norm) should come first. The skipped ones are
+//! reported per tensor.
+
+use super::{create_strategy_for, weight_round_trip_mse, QuantizationStrategy, StrategyType};
+use crate::core::QuantFormat;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Winning candidate of one tensor.
+struct Selection {
+    strategy: &'static str,
+    scales: Option<Vec<f32>>,
+    /// Candidates not started because the time budget ran out
+    skipped: Vec<&'static str>,
+}
+
+/// Output of one candidate strategy and its weight error.
+struct Candidate {
+    mse: f32,
+    permuted: Vec<f32>,
+    perm: Option<Vec<usize>>,
+    selection: Selection,
+}
+
+pub struct AutoStrategy {
+    candidates: Vec<Box<dyn QuantizationStrategy>>,
+    time_budget: Option<Duration>,
+    format: QuantFormat,
+    selections: Mutex<HashMap<String, Selection>>,
+}
+
+impl AutoStrategy {
+    pub fn new(
+        candidates: &[StrategyType],
+        time_budget: Option<Duration>,
+        format: QuantFormat,
+    ) -> Result<Self> {
+        if candidates.is_empty() {
+            bail!("auto strategy needs at least one candidate");
+        }
+        let candidates = candidates
+            .iter()
+            .map(|c| match c {
+                StrategyType::Auto { .. } => bail!("auto strategy cannot be its own candidate"),
+                c => create_strategy_for(c, format),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            candidates,
+            time_budget,
+            format,
+            selections: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Identity, L2 norm and QR pivoting.
+    pub fn default_candidates() -> Vec<StrategyType> {
+        vec![
+            StrategyType::Identity,
+            StrategyType::L2Norm,
+            StrategyType::QRPivot,
+        ]
+    }
+}
+
+impl QuantizationStrategy for AutoStrategy {
+    fn apply_permutation(
+        &self,
+        data: &[f32],
+        rows: usize,
+        k: usize,
+        tensor_name: &str,
+    ) -> Result<(Vec<f32>, Option<Vec<usize>>)> {
+        let start = Instant::now();
+        let mut best: Option<Candidate> = None;
+        let mut tried = Vec::new();
+        let mut skipped = Vec::new();
+        for (i, candidate) in self.candidates.iter().enumerate() {
+            if best.is_some() && self.time_budget.is_some_and(|b| start.elapsed() >= b) {
+                skipped = self.candidates[i..].iter().map(|c| c.name()).collect();
+                break;
+            }
+            let (permuted, perm) = candidate.apply_permutation(data, rows, k, tensor_name)?;
+            let scales = candidate.take_column_scales(tensor_name);
+            let mse = weight_round_trip_mse(
+                self.format,
+                (rows, k),
+                &permuted,
+                perm.as_deref(),
+                scales.as_deref(),
+            )?;
+            tried.push(format!("{} {:.4e}", candidate.name(), mse));
+            if best.as_ref().is_none_or(|b| mse < b.mse) {
+                best = Some(Candidate {
+                    mse,
+                    permuted,
+                    perm,
+                    selection: Selection {
+                        strategy: candidate.name(),
+                        scales,
+                        skipped: Vec::new(),
+                    },
+                });
+            }
+        }
+        let mut best = best.expect("candidates are non-empty");
+        let strategy = best.selection.strategy;
+        if skipped.is_empty() {
+            println!("  auto: {strategy} (MSE {})", tried.join(", "));
+        } else {
+            println!(
+                "  auto: {strategy} (MSE {}; over budget, skipped {})",
+                tried.join(", "),
+                skipped.join(", ")
+            );
+        }
+        best.selection.skipped = skipped;
+        self.selections
+            .lock()
+            .unwrap()
+            .insert(tensor_name.to_string(), best.selection);
+        Ok((best.permuted, best.perm))
+    }
+
+    fn take_column_scales(&self, tensor_name: &str) -> Option<Vec<f32>> {
+        let mut selections = self.selections.lock().unwrap();
+        selections.get_mut(tensor_name)?.scales.take()
+    }
+
+    fn shared_state_key(&self, tensor_name: &str) -> Option<String> {
+        self.candidates
+            .iter()
+            .find_map(|c| c.shared_state_key(tensor_name))
+    }
+
+    fn take_skipped_candidates(&self, tensor_name: &str) -> Vec<&'static str> {
+        let mut selections = self.selections.lock().unwrap();
+        selections
+            .get_mut(tensor_name)
+            .map(|s| std::mem::take(&mut s.skipped))
+            .unwrap_or_default()
+    }
+
+    fn take_selected_strategy(&self, tensor_name: &str) -> Option<&'static str> {
+        let selection = self.selections.lock().unwrap().remove(tensor_name)?;
+        Some(selection.strategy)
+    }
+
+    fn name(&self) -> &'static str {
+        "Auto"
+    }
+}
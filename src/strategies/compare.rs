@@ -0,0 +1,284 @@
+//! Side-by-side comparison of strategies on one model.
+//!
+//! Every strategy orders the same tensors, and each ordering is rounded to the output format
+//! and scored by its weight MSE in the original column space. Nothing is written; the result
+//! is a table of error, runtime and how far the strategies agree on which columns share a
+//! block.
+
+use super::{
+    create_strategy_for, registered_strategies, weight_round_trip_mse, QuantizationStrategy,
+    StrategyType,
+};
+use crate::core::{skip_reason, ModelSource, QuantFormat, QuantizationConfig};
+use anyhow::{bail, Result};
+use candle_core::quantized::k_quants::QK_K;
+use serde::Serialize;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct CompareConfig {
+    pub strategies: Vec<StrategyType>,
+    pub format: QuantFormat,
+    pub skip_patterns: Vec<String>,
+    /// Compare on at most this many tensors, evenly spaced through the model
+    pub sample_tensors: Option<usize>,
+}
+
+impl Default for CompareConfig {
+    fn default() -> Self {
+        Self {
+            strategies: registered_strategies(None),
+            format: QuantFormat::Q8K,
+            skip_patterns: QuantizationConfig::default().skip_patterns,
+            sample_tensors: None,
+        }
+    }
+}
+
+/// One strategy on one tensor.
+#[derive(Debug, Clone, Serialize)]
+pub struct StrategyRun {
+    pub strategy: String,
+    pub mse: f32,
+    /// Relative error reduction over unpermuted rounding, `1 - mse / unpermuted_mse`
+    pub improvement: f32,
+    /// Time spent in the strategy itself, excluding the error measurement
+    pub seconds: f32,
+    /// FNV-1a hash of the permutation; `None` when the columns were left in place
+    pub perm_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TensorComparison {
+    pub name: String,
+    pub shape: Vec<usize>,
+    pub unpermuted_mse: f32,
+    /// In `CompareConfig::strategies` order
+    pub runs: Vec<StrategyRun>,
+}
+
+/// One strategy over all compared tensors.
+#[derive(Debug, Clone, Serialize)]
+pub struct StrategySummary {
+    pub strategy: String,
+    pub mean_mse: f32,
+    pub mean_improvement: f32,
+    /// Tensors on which this strategy had the lowest error (ties go to the first)
+    pub wins: usize,
+    pub total_seconds: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StrategyComparison {
+    pub input: PathBuf,
+    pub format: String,
+    pub tensors: Vec<TensorComparison>,
+    pub strategies: Vec<StrategySummary>,
+    /// Mean block overlap between each pair of strategies, indexed like `strategies`: the
+    /// fraction of columns that land in a block together with the same columns under both
+    /// orderings (1 for identical block partitions)
+    pub overlap: Vec<Vec<f32>>,
+}
+
+/// Run every strategy of `config` over the eligible tensors of `input_path`.
+pub fn compare_strategies(input_path: &Path, config: &CompareConfig) -> Result<StrategyComparison> {
+    if config.strategies.is_empty() {
+        bail!("no strategies to compare");
+    }
+    let strategies = config
+        .strategies
+        .iter()
+        .map(|s| create_strategy_for(s, config.format))
+        .collect::<Result<Vec<Box<dyn QuantizationStrategy>>>>()?;
+    let source = ModelSource::open(input_path)?;
+    let mut eligible: Vec<_> = source
+        .tensors()
+        .iter()
+        .filter(|t| skip_reason(t, &config.skip_patterns).is_none())
+        .collect();
+    if let Some(n) = config.sample_tensors.filter(|&n| n < eligible.len()) {
+        let stride = eligible.len() as f64 / n.max(1) as f64;
+        eligible = (0..n)
+            .map(|i| eligible[(i as f64 * stride) as usize])
+            .collect();
+    }
+
+    let n = strategies.len();
+    let mut tensors = Vec::with_capacity(eligible.len());
+    let mut overlap_sum = vec![vec![0f64; n]; n];
+    for tensor in eligible {
+        let (rows, k) = (tensor.shape[0], tensor.shape[1]);
+        println!("comparing {} ({rows} x {k})", tensor.name);
+        let data = source.tensor_f32(tensor)?;
+        source.release(tensor);
+        let unpermuted_mse = weight_round_trip_mse(config.format, (rows, k), &data, None, None)?;
+
+        let mut runs = Vec::with_capacity(n);
+        let mut perms = Vec::with_capacity(n);
+        for strategy in &strategies {
+            let start = Instant::now();
+            let (permuted, perm) = strategy.apply_permutation(&data, rows, k, &tensor.name)?;
+            let scales = strategy.take_column_scales(&tensor.name);
+            let seconds = start.elapsed().as_secs_f32();
+            let name = strategy
+                .take_selected_strategy(&tensor.name)
+                .map_or_else(|| strategy.name().to_string(), |s| format!("Auto({s})"));
+            let mse = weight_round_trip_mse(
+                config.format,
+                (rows, k),
+                &permuted,
+                perm.as_deref(),
+                scales.as_deref(),
+            )?;
+            println!("  {name}: MSE {mse:.6e} in {seconds:.3}s");
+            runs.push(StrategyRun {
+                strategy: name,
+                mse,
+                improvement: improvement(mse, unpermuted_mse),
+                seconds,
+                perm_hash: perm
+                    .as_deref()
+                    .map(|p| format!("{:016x}", crate::utils::permutation_hash(p))),
+            });
+            perms.push(perm);
+        }
+        for a in 0..n {
+            for b in 0..n {
+                overlap_sum[a][b] +=
+                    block_overlap(perms[a].as_deref(), perms[b].as_deref(), k, QK_K) as f64;
+            }
+        }
+        tensors.push(TensorComparison {
+            name: tensor.name.clone(),
+            shape: tensor.shape.clone(),
+            unpermuted_mse,
+            runs,
+        });
+    }
+
+    let count = tensors.len().max(1) as f64;
+    let summaries = strategies
+        .iter()
+        .enumerate()
+        .map(|(i, strategy)| {
+            let runs = tensors.iter().map(|t| &t.runs[i]);
+            let mean = |f: &dyn Fn(&StrategyRun) -> f32| {
+                (runs.clone().map(|r| f(r) as f64).sum::<f64>() / count) as f32
+            };
+            StrategySummary {
+                strategy: strategy.name().to_string(),
+                mean_mse: mean(&|r| r.mse),
+                mean_improvement: mean(&|r| r.improvement),
+                wins: tensors
+                    .iter()
+                    .filter(|t| {
+                        let best = t.runs.iter().map(|r| r.mse).fold(f32::INFINITY, f32::min);
+                        t.runs.iter().position(|r| r.mse == best) == Some(i)
+                    })
+                    .count(),
+                total_seconds: runs.map(|r| r.seconds).sum(),
+            }
+        })
+        .collect();
+    let overlap = if tensors.is_empty() {
+        vec![vec![1.0; n]; n]
+    } else {
+        overlap_sum
+            .iter()
+            .map(|row| row.iter().map(|&s| (s / count) as f32).collect())
+            .collect()
+    };
+
+    Ok(StrategyComparison {
+        input: input_path.to_path_buf(),
+        format: config.format.name().to_string(),
+        tensors,
+        strategies: summaries,
+        overlap,
+    })
+}
+
+impl StrategyComparison {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Per-strategy summary followed by the block overlap matrix, as aligned text.
+    pub fn to_table(&self) -> String {
+        let width = self
+            .strategies
+            .iter()
+            .map(|s| s.strategy.len())
+            .max()
+            .unwrap_or(0)
+            .max("strategy".len());
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{:width$}  {:>12}  {:>14}  {:>5}  {:>10}",
+            "strategy", "mean MSE", "vs unpermuted", "wins", "seconds"
+        );
+        for s in &self.strategies {
+            let _ = writeln!(
+                out,
+                "{:width$}  {:>12.4e}  {:>+13.2}%  {:>5}  {:>10.3}",
+                s.strategy,
+                s.mean_mse,
+                s.mean_improvement * 100.0,
+                s.wins,
+                s.total_seconds
+            );
+        }
+        let _ = writeln!(out, "\nblock overlap (%)");
+        let _ = write!(out, "{:width$}", "");
+        for s in &self.strategies {
+            let _ = write!(out, "  {:>w$}", s.strategy, w = s.strategy.len().max(6));
+        }
+        let _ = writeln!(out);
+        for (s, row) in self.strategies.iter().zip(&self.overlap) {
+            let _ = write!(out, "{:width$}", s.strategy);
+            for (t, v) in self.strategies.iter().zip(row) {
+                let _ = write!(out, "  {:>w$.1}", v * 100.0, w = t.strategy.len().max(6));
+            }
+            let _ = writeln!(out);
+        }
+        out
+    }
+}
+
+fn improvement(mse: f32, unpermuted_mse: f32) -> f32 {
+    if unpermuted_mse > 0.0 {
+        1.0 - mse / unpermuted_mse
+    } else {
+        0.0
+    }
+}
+
+/// Fraction of columns that share their block with the same columns under both orderings
+/// (`None` is the identity), averaged over matching the blocks of `a` to those of `b` and
+/// the other way round.
+fn block_overlap(a: Option<&[usize]>, b: Option<&[usize]>, k: usize, block: usize) -> f32 {
+    (block_matching(a, b, k, block) + block_matching(b, a, k, block)) / 2.0
+}
+
+/// Fraction of columns in place when every block of `a` is matched with the block of `b`
+/// holding most of its columns.
+fn block_matching(a: Option<&[usize]>, b: Option<&[usize]>, k: usize, block: usize) -> f32 {
+    let mut block_in_b = vec![0usize; k];
+    for pos in 0..k {
+        block_in_b[b.map_or(pos, |p| p[pos])] = pos / block;
+    }
+    let mut counts = vec![0usize; k.div_ceil(block)];
+    let mut shared = 0;
+    for start in (0..k).step_by(block) {
+        let end = (start + block).min(k);
+        counts.iter_mut().for_each(|c| *c = 0);
+        for pos in start..end {
+            counts[block_in_b[a.map_or(pos, |p| p[pos])]] += 1;
+        }
+        shared += counts.iter().max().copied().unwrap_or(0);
+    }
+    shared as f32 / k.max(1) as f32
+}
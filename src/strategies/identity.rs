@@ -0,0 +1,23 @@
+//! Identity strategy: keeps the original column order.
+
+use super::QuantizationStrategy;
+use anyhow::Result;
+
+/// Leaves the columns where they are; the reference point for other orderings.
+pub struct IdentityStrategy;
+
+impl QuantizationStrategy for IdentityStrategy {
+    fn apply_permutation(
+        &self,
+        data: &[f32],
+        _rows: usize,
+        _k: usize,
+        _tensor_name: &str,
+    ) -> Result<(Vec<f32>, Option<Vec<usize>>)> {
+        Ok((data.to_vec(), None))
+    }
+
+    fn name(&self) -> &'static str {
+        "Identity"
+    }
+}
@@ -6,6 +6,12 @@ use anyhow::Result;
 
 pub struct L2NormStrategy;
 
+impl Default for L2NormStrategy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl L2NormStrategy {
     pub fn new() -> Self {
         Self
@@ -1,7 +1,36 @@
 //! Learnable permutation strategies using gradient descent.
+//!
+//! Q8K picks one scale per `QK_K` wide block of a row, so the reconstruction error only
+//! depends on which block each column lands in. The Gumbel-Sinkhorn soft permutation is
+//! therefore kept in block-collapsed form: a `k x (k / QK_K)` matrix whose rows sum to one
+//! and whose columns sum to `QK_K` (the block sums of the full doubly stochastic matrix).
 
-use super::QuantizationStrategy;
+use super::{q8k_round_trip, QuantizationStrategy};
+use crate::utils::{
+    apply_column_permutation, build_column_permutation, column_l2_norms, hungarian_block_assignment,
+};
 use anyhow::Result;
+use candle_core::quantized::k_quants::QK_K;
+use candle_core::{DType, Device, Tensor, Var};
+use candle_nn::optim::{AdamW, Optimizer};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Rows sampled from the weight matrix to build the training objective.
+const SAMPLE_ROWS: usize = 128;
+/// Sinkhorn normalisation rounds per optimizer step.
+const SINKHORN_ITERS: usize = 10;
+/// Exponent of the p-norm used as a differentiable stand-in for the block max.
+const SMOOTH_MAX_P: f64 = 8.0;
+/// Gumbel temperature, annealed geometrically from start to end.
+const TAU_START: f64 = 1.0;
+const TAU_END: f64 = 0.05;
+/// Scale of the Gumbel noise added to the logits.
+const NOISE_SCALE: f64 = 0.1;
+/// Logit bias towards the L2 ordering used to warm-start the optimizer.
+const INIT_BIAS: f32 = 2.0;
 
 pub struct LearnableStrategy {
     learning_rate: f64,
@@ -10,29 +39,176 @@ pub struct LearnableStrategy {
 
 impl LearnableStrategy {
     pub fn new(learning_rate: f64, iterations: usize) -> Self {
-        Self { learning_rate, iterations }
+        Self {
+            learning_rate,
+            iterations,
+        }
+    }
+
+    /// Learn block logits and round them to a hard permutation.
+    fn learn_permutation(
+        &self,
+        data: &[f32],
+        rows: usize,
+        k: usize,
+        l2_perm: &[usize],
+        seed: u64,
+    ) -> Result<Vec<usize>> {
+        let device = Device::Cpu;
+        let nb = k / QK_K;
+
+        // Objective input: sampled |w|^p, normalised so the p-th power stays in range.
+        let sample = rows.min(SAMPLE_ROWS);
+        let stride = rows / sample;
+        let max_abs = data
+            .iter()
+            .fold(0f32, |m, &v| m.max(v.abs()))
+            .max(f32::MIN_POSITIVE);
+        let mut xs = Vec::with_capacity(sample * k);
+        for s in 0..sample {
+            let r = s * stride;
+            xs.extend(
+                data[r * k..(r + 1) * k]
+                    .iter()
+                    .map(|&v| (v.abs() / max_abs).powf(SMOOTH_MAX_P as f32)),
+            );
+        }
+        let xs = Tensor::from_vec(xs, (sample, k), &device)?;
+
+        let mut init = vec![0f32; k * nb];
+        for (pos, &col) in l2_perm.iter().enumerate() {
+            init[col * nb + pos / QK_K] = INIT_BIAS;
+        }
+        let log_alpha = Var::from_vec(init, (k, nb), &device)?;
+        let mut opt = AdamW::new_lr(vec![log_alpha.clone()], self.learning_rate)?;
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let log_capacity = (QK_K as f64).ln();
+        let steps = self.iterations.max(1);
+        for step in 0..self.iterations {
+            let frac = step as f64 / steps as f64;
+            let tau = TAU_START * (TAU_END / TAU_START).powf(frac);
+
+            let noise: Vec<f32> = (0..k * nb)
+                .map(|_| {
+                    let u: f32 = rng.random::<f32>().clamp(1e-10, 1.0 - 1e-7);
+                    -(-u.ln()).ln() * NOISE_SCALE as f32
+                })
+                .collect();
+            let noise = Tensor::from_vec(noise, (k, nb), &device)?;
+
+            let mut log_p = ((log_alpha.as_tensor() + noise)? / tau)?;
+            for _ in 0..SINKHORN_ITERS {
+                log_p = log_p.broadcast_sub(&log_p.log_sum_exp(1)?.unsqueeze(1)?)?;
+                let col = (log_p.log_sum_exp(0)?.unsqueeze(0)? - log_capacity)?;
+                log_p = log_p.broadcast_sub(&col)?;
+            }
+            let assign = log_p.exp()?;
+
+            // Smooth block max per sampled row; squared scale drives the Q8K error.
+            let block_mass = xs.matmul(&assign)?;
+            let loss = (block_mass + 1e-6)?.powf(2.0 / SMOOTH_MAX_P)?.mean_all()?;
+            opt.backward_step(&loss)?;
+        }
+
+        let logits = log_alpha.as_tensor().to_dtype(DType::F64)?.flatten_all()?;
+        let cost: Vec<f64> = logits.to_vec1::<f64>()?.into_iter().map(|v| -v).collect();
+        let block_of = hungarian_block_assignment(&cost, k, nb, QK_K)?;
+
+        // Order by block, keeping the L2 order inside each block.
+        let mut perm = Vec::with_capacity(k);
+        for b in 0..nb {
+            perm.extend(l2_perm.iter().copied().filter(|&c| block_of[c] == b));
+        }
+        Ok(perm)
     }
 }
 
+/// Mean squared error after a BlockQ8K round trip of already permuted data.
+fn q8k_reconstruction_mse(rows: usize, k: usize, data: &[f32]) -> Result<f64> {
+    let restored = q8k_round_trip(rows, k, data)?;
+    let sum: f64 = data
+        .iter()
+        .zip(restored.iter())
+        .map(|(a, b)| {
+            let d = (*a - *b) as f64;
+            d * d
+        })
+        .sum();
+    Ok(sum / data.len().max(1) as f64)
+}
+
 impl QuantizationStrategy for LearnableStrategy {
     fn apply_permutation(
         &self,
         data: &[f32],
         rows: usize,
         k: usize,
-        _tensor_name: &str,
+        tensor_name: &str,
     ) -> Result<(Vec<f32>, Option<Vec<usize>>)> {
-        // TODO: Implement Gumbel-Softmax learnable permutation
-        // For now, fall back to L2 norm
-        use crate::utils::{column_l2_norms, build_column_permutation, apply_column_permutation};
-        
         let norms = column_l2_norms(rows, k, data);
-        let perm = build_column_permutation(&norms);
-        let permuted = apply_column_permutation(rows, k, data, &perm);
-        Ok((permuted, Some(perm)))
+        let l2_perm = build_column_permutation(&norms);
+        let l2_data = apply_column_permutation(rows, k, data, &l2_perm);
+
+        // A single block has one scale whatever the order; nothing to learn.
+        if k / QK_K < 2 || rows == 0 || self.iterations == 0 {
+            return Ok((l2_data, Some(l2_perm)));
+        }
+
+        let mut hasher = DefaultHasher::new();
+        tensor_name.hash(&mut hasher);
+        let perm = self.learn_permutation(data, rows, k, &l2_perm, hasher.finish())?;
+        let learned = apply_column_permutation(rows, k, data, &perm);
+
+        let learned_mse = q8k_reconstruction_mse(rows, k, &learned)?;
+        let l2_mse = q8k_reconstruction_mse(rows, k, &l2_data)?;
+        if learned_mse > l2_mse {
+            println!(
+                "  Learned permutation for {} worse than L2 ({:.6e} vs {:.6e}), falling back",
+                tensor_name, learned_mse, l2_mse
+            );
+            return Ok((l2_data, Some(l2_perm)));
+        }
+
+        Ok((learned, Some(perm)))
     }
 
     fn name(&self) -> &'static str {
         "Learnable"
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn learned_permutation_is_a_bijection_no_worse_than_l2() {
+        // Two blocks, with a few outlier columns for the permutation to separate
+        let (rows, k) = (32, 2 * QK_K);
+        let data: Vec<f32> = (0..rows * k)
+            .map(|i| {
+                let v = ((i * 7919 % 1009) as f32 / 1009.0 - 0.5) * 0.1;
+                if (i % k) % 37 == 0 {
+                    v * 40.0
+                } else {
+                    v
+                }
+            })
+            .collect();
+        let strategy = LearnableStrategy::new(0.05, 50);
+        let (permuted, perm) = strategy.apply_permutation(&data, rows, k, "t").unwrap();
+        let perm = perm.unwrap();
+
+        let mut sorted = perm.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..k).collect::<Vec<_>>());
+        assert_eq!(permuted, apply_column_permutation(rows, k, &data, &perm));
+
+        let l2_perm = build_column_permutation(&column_l2_norms(rows, k, &data));
+        let l2_data = apply_column_permutation(rows, k, &data, &l2_perm);
+        let mse = q8k_reconstruction_mse(rows, k, &permuted).unwrap();
+        let l2_mse = q8k_reconstruction_mse(rows, k, &l2_data).unwrap();
+        assert!(mse <= l2_mse, "{mse} > L2 {l2_mse}");
+    }
+}
@@ -1,23 +1,42 @@
 //! Quantization strategies.
 
+pub mod activation_aware;
 pub mod attention_aware;
+pub mod auto;
+mod compare;
+pub mod identity;
 pub mod l2_norm;
+pub mod learnable;
+mod pipeline;
 pub mod qr_pivot;
-// pub mod learnable;
 
+pub use activation_aware::ActivationAwareStrategy;
 pub use attention_aware::AttentionAwareStrategy;
+pub use auto::AutoStrategy;
+pub use compare::{
+    compare_strategies, CompareConfig, StrategyComparison, StrategyRun, StrategySummary,
+    TensorComparison,
+};
+pub use identity::IdentityStrategy;
 pub use l2_norm::L2NormStrategy;
+pub use learnable::LearnableStrategy;
+pub use pipeline::run_quantization;
 pub use qr_pivot::QRPivotStrategy;
 
-use crate::core::{QuantizationConfig, QuantizationResult};
-use anyhow::Result;
-use std::path::Path;
+use crate::core::QuantFormat;
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use candle_core::quantized::k_quants::{BlockQ8K, QK_K};
+use candle_core::quantized::k_quants::{
+    BlockQ2K, BlockQ3K, BlockQ4K, BlockQ5K, BlockQ6K, BlockQ8K,
+};
 use candle_core::quantized::GgmlType;
 
 #[derive(Debug, Clone)]
 pub enum StrategyType {
+    /// Original column order
+    Identity,
     L2Norm,
     AttentionAware,
     QRPivot,
@@ -25,9 +44,65 @@ pub enum StrategyType {
         learning_rate: f64,
         iterations: usize,
     },
+    ActivationAware {
+        calibration_path: PathBuf,
+    },
+    /// Try each candidate on every tensor and keep the lowest weight error. Once
+    /// `time_budget` has elapsed on a tensor, no new candidate is started; the one running
+    /// when it expires finishes, so a tensor can overrun the budget by one candidate. The
+    /// candidates not started go into the tensor's report.
+    Auto {
+        candidates: Vec<StrategyType>,
+        time_budget: Option<Duration>,
+    },
+}
+
+impl StrategyType {
+    /// Name of the strategy this creates, as returned by `QuantizationStrategy::name`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StrategyType::Identity => "Identity",
+            StrategyType::L2Norm => "L2Norm",
+            StrategyType::AttentionAware => "AttentionAware",
+            StrategyType::QRPivot => "QRPivot",
+            StrategyType::Learnable { .. } => "Learnable",
+            StrategyType::ActivationAware { .. } => "ActivationAware",
+            StrategyType::Auto { .. } => "Auto",
+        }
+    }
+
+    /// Parameters as comma-separated `key=value` pairs, as recorded in `.q8k` headers; empty
+    /// for strategies without any.
+    pub fn params(&self) -> String {
+        match self {
+            StrategyType::Learnable {
+                learning_rate,
+                iterations,
+            } => format!("learning_rate={learning_rate},iterations={iterations}"),
+            StrategyType::ActivationAware { calibration_path } => format!(
+                "calibration={}",
+                calibration_path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+            ),
+            StrategyType::Auto {
+                candidates,
+                time_budget,
+            } => {
+                let names: Vec<&str> = candidates.iter().map(StrategyType::name).collect();
+                let mut params = format!("candidates={}", names.join("+"));
+                if let Some(budget) = time_budget {
+                    params.push_str(&format!(",time_budget={budget:?}"));
+                }
+                params
+            }
+            _ => String::new(),
+        }
+    }
 }
 
-pub trait QuantizationStrategy {
+pub trait QuantizationStrategy: Send + Sync {
     /// Apply permutation strategy to the given data
     fn apply_permutation(
         &self,
@@ -37,138 +112,163 @@ pub trait QuantizationStrategy {
         tensor_name: &str,
     ) -> Result<(Vec<f32>, Option<Vec<usize>>)>;
 
+    /// Per-input-channel scales folded into the data returned by `apply_permutation` for
+    /// this tensor, in original column order. Strategies that only permute return `None`.
+    fn take_column_scales(&self, _tensor_name: &str) -> Option<Vec<f32>> {
+        None
+    }
+
+    /// Tensors with the same key share state inside the strategy (e.g. one permutation for
+    /// several projections). They are handed to `apply_permutation` one after another, in
+    /// model order, so the result does not depend on scheduling.
+    fn shared_state_key(&self, _tensor_name: &str) -> Option<String> {
+        None
+    }
+
+    /// Candidates a strategy that picks one per tensor did not try on this tensor. Called
+    /// once after `apply_permutation`, before `take_selected_strategy`.
+    fn take_skipped_candidates(&self, _tensor_name: &str) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    /// Name of the strategy that actually ordered this tensor, for strategies that pick one
+    /// per tensor. Called once after `apply_permutation`.
+    fn take_selected_strategy(&self, _tensor_name: &str) -> Option<&'static str> {
+        None
+    }
+
     /// Get strategy name for logging
     fn name(&self) -> &'static str;
 }
 
-pub fn create_strategy(strategy_type: &StrategyType) -> Box<dyn QuantizationStrategy> {
-    match strategy_type {
+pub fn create_strategy(strategy_type: &StrategyType) -> Result<Box<dyn QuantizationStrategy>> {
+    create_strategy_for(strategy_type, QuantFormat::Q8K)
+}
+
+/// Like `create_strategy`, for output in `format` (`StrategyType::Auto` measures its
+/// candidates and `StrategyType::ActivationAware` its `alpha` grid in the output format).
+pub fn create_strategy_for(
+    strategy_type: &StrategyType,
+    format: QuantFormat,
+) -> Result<Box<dyn QuantizationStrategy>> {
+    Ok(match strategy_type {
+        StrategyType::Identity => Box::new(IdentityStrategy),
         StrategyType::L2Norm => Box::new(L2NormStrategy::new()),
         StrategyType::AttentionAware => Box::new(AttentionAwareStrategy::new()),
         StrategyType::QRPivot => {
             Box::new(QRPivotStrategy::new(1e-8)) // Small regularization
         }
-        StrategyType::Learnable { .. } => {
-            unimplemented!("Learnable strategy not yet implemented")
-        }
-    }
+        StrategyType::Learnable {
+            learning_rate,
+            iterations,
+        } => Box::new(LearnableStrategy::new(*learning_rate, *iterations)),
+        StrategyType::ActivationAware { calibration_path } => Box::new(
+            ActivationAwareStrategy::from_file(calibration_path, format)?,
+        ),
+        StrategyType::Auto {
+            candidates,
+            time_budget,
+        } => Box::new(AutoStrategy::new(candidates, *time_budget, format)?),
+    })
 }
 
-pub fn run_quantization(
-    input_path: &Path,
-    config: QuantizationConfig,
-) -> Result<QuantizationResult> {
-    use crate::core::io::{write_perm, write_q8k};
-    use crate::core::validation::{validate_quantization, validate_quantization_direct};
-    use crate::utils::{is_target_weight, tensor_to_f32};
-    use safetensors::SafeTensors;
-    use std::{fs, time::Instant};
-
-    let start_time = Instant::now();
-    fs::create_dir_all(&config.output_dir)?;
-
-    let bytes = fs::read(input_path)?;
-    let st = SafeTensors::deserialize(&bytes)?;
-
-    let mut quantized_count = 0;
-    let mut skipped_count = 0;
-    let mut mse_stats = Vec::new();
-
-    let strategy = if config.use_permutation {
-        Some(create_strategy(&config.strategy_type))
-    } else {
-        None
-    };
-
-    println!("Tensors: {}", st.len());
-
-    for name in st.names() {
-        let tensor = st.tensor(name)?;
-        let shape = tensor.shape();
-
-        if shape.len() != 2 || !is_target_weight(name, &config.skip_patterns) {
-            skipped_count += 1;
-            continue;
-        }
-
-        let (rows, k) = (shape[0], shape[1]);
-        if k % QK_K != 0 {
-            println!("skip (k % {QK_K} != 0): {name} [{rows} x {k}]");
-            skipped_count += 1;
-            continue;
-        }
-
-        println!("quantizing {name} ({rows} x {k})");
-
-        // Load weights to f32
-        let data_f32 = tensor_to_f32(tensor.data(), tensor.dtype())?;
-
-        // Apply permutation strategy if enabled
-        let (data_for_quant, maybe_perm) = if let Some(ref strat) = strategy {
-            strat.apply_permutation(&data_f32, rows, k, name)?
-        } else {
-            (data_f32, None)
-        };
-
-        // Quantize to BlockQ8K
-        let blocks = quantize_rows_q8k(rows, k, &data_for_quant)?;
-
-        // Validate quantization quality
-
-        let mse_matmul = validate_quantization(&data_for_quant, &blocks, k)?;
-        let mse_direct = validate_quantization_direct(&data_for_quant, &blocks, k)?;
-        // Compare and log the results
-        let diff = (mse_matmul - mse_direct).abs();
-        if diff > 1e-6 {
-            println!("    [INFO] Validation methods differ by {:.8e}", diff);
-        }
-        mse_stats.push((name.to_string(), mse_matmul, mse_direct));
-
-        println!(
-            "  MSE (matmul): {:.6e}, MSE (direct): {:.6e}",
-            mse_matmul, mse_direct
-        );
-
-        let diff = (mse_matmul - mse_direct).abs();
-        if diff > 1e-6 {
-            println!("    [INFO] Validation methods differ by {:.8e}", diff);
-        }
-        if mse_matmul > 1e-2 || mse_direct > 1e-2 {
-            println!("    [WARN] High MSE detected - quantization may be lossy");
-        }
-
-        // Write quantized data
-        let out_path = config.output_dir.join(format!("{}.q8k", name));
-        write_q8k(&out_path, rows, k, &blocks)?;
-
-        // Write permutation if used
-        if let Some(perm) = maybe_perm {
-            write_perm(&out_path, &perm)?;
-        }
-
-        quantized_count += 1;
+/// Every strategy with its default parameters, `Auto` last. `ActivationAware` is included
+/// only when calibration statistics are given.
+pub fn registered_strategies(calibration_path: Option<&Path>) -> Vec<StrategyType> {
+    let mut strategies = vec![
+        StrategyType::Identity,
+        StrategyType::L2Norm,
+        StrategyType::AttentionAware,
+        StrategyType::QRPivot,
+        StrategyType::Learnable {
+            learning_rate: 0.01,
+            iterations: 1000,
+        },
+    ];
+    if let Some(path) = calibration_path {
+        strategies.push(StrategyType::ActivationAware {
+            calibration_path: path.to_path_buf(),
+        });
     }
-
-    Ok(QuantizationResult {
-        quantized_tensors: quantized_count,
-        skipped_tensors: skipped_count,
-        total_time_seconds: start_time.elapsed().as_secs_f32(),
-        mse_stats,
-    })
+    strategies.push(StrategyType::Auto {
+        candidates: AutoStrategy::default_candidates(),
+        time_budget: None,
+    });
+    strategies
 }
 
-fn quantize_rows_q8k(rows: usize, k: usize, data: &[f32]) -> Result<Vec<BlockQ8K>> {
-    use anyhow::bail;
-
-    if k % QK_K != 0 {
-        bail!("inner dim {k} not multiple of {QK_K}");
+pub(crate) fn quantize_rows<T: GgmlType>(rows: usize, k: usize, data: &[f32]) -> Result<Vec<T>> {
+    if !k.is_multiple_of(T::BLCK_SIZE) {
+        bail!("inner dim {k} not multiple of {}", T::BLCK_SIZE);
     }
-    let blocks_per_row = k / QK_K;
-    let mut blocks = vec![BlockQ8K::zeros(); rows * blocks_per_row];
+    let blocks_per_row = k / T::BLCK_SIZE;
+    let mut blocks = vec![T::zeros(); rows * blocks_per_row];
     for r in 0..rows {
         let row = &data[r * k..(r + 1) * k];
         let dst = &mut blocks[r * blocks_per_row..(r + 1) * blocks_per_row];
-        BlockQ8K::from_float(row, dst)?;
+        T::from_float(row, dst);
     }
     Ok(blocks)
 }
+
+/// Quantize to `T` and immediately dequantize, for reconstruction error estimates.
+pub(crate) fn round_trip<T: GgmlType>(rows: usize, k: usize, data: &[f32]) -> Result<Vec<f32>> {
+    let blocks = quantize_rows::<T>(rows, k, data)?;
+    let mut restored = vec![0f32; data.len()];
+    T::to_float(&blocks, &mut restored);
+    Ok(restored)
+}
+
+/// `round_trip` with the block type of `format`.
+pub(crate) fn format_round_trip(
+    format: QuantFormat,
+    rows: usize,
+    k: usize,
+    data: &[f32],
+) -> Result<Vec<f32>> {
+    match format {
+        QuantFormat::Q8K => round_trip::<BlockQ8K>(rows, k, data),
+        QuantFormat::Q6K => round_trip::<BlockQ6K>(rows, k, data),
+        QuantFormat::Q5K => round_trip::<BlockQ5K>(rows, k, data),
+        QuantFormat::Q4K => round_trip::<BlockQ4K>(rows, k, data),
+        QuantFormat::Q3K => round_trip::<BlockQ3K>(rows, k, data),
+        QuantFormat::Q2K => round_trip::<BlockQ2K>(rows, k, data),
+    }
+}
+
+/// Quantize to BlockQ8K and immediately dequantize, for reconstruction error estimates.
+pub(crate) fn q8k_round_trip(rows: usize, k: usize, data: &[f32]) -> Result<Vec<f32>> {
+    round_trip::<BlockQ8K>(rows, k, data)
+}
+
+/// Weight MSE of plain rounding to `format`, in the source column space: quantized column `i`
+/// holds source column `perm[i]` times `scales[perm[i]]`, so the scale is divided back out.
+pub(crate) fn weight_round_trip_mse(
+    format: QuantFormat,
+    (rows, k): (usize, usize),
+    data: &[f32],
+    perm: Option<&[usize]>,
+    scales: Option<&[f32]>,
+) -> Result<f32> {
+    let restored = format_round_trip(format, rows, k, data)?;
+    Ok(source_space_mse(k, data, &restored, perm, scales))
+}
+
+/// MSE of `restored` against `data`, laid out as in `weight_round_trip_mse`, in the source
+/// column space.
+pub(crate) fn source_space_mse(
+    k: usize,
+    data: &[f32],
+    restored: &[f32],
+    perm: Option<&[usize]>,
+    scales: Option<&[f32]>,
+) -> f32 {
+    let mut sum = 0f64;
+    for (i, (&d, &q)) in data.iter().zip(restored).enumerate() {
+        let col = i % k;
+        let divisor = scales.map_or(1.0, |s| s[perm.map_or(col, |p| p[col])]);
+        let e = ((d - q) / divisor) as f64;
+        sum += e * e;
+    }
+    (sum / data.len().max(1) as f64) as f32
+}
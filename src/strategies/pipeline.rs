@@ -0,0 +1,899 @@
+//! The quantization pipeline: read a tensor, apply the strategy, quantize, validate and write.
+//!
+//! Tensors are handed out to worker threads in model order. Tensors sharing strategy state
+//! (see `QuantizationStrategy::shared_state_key`) form one work item processed in order, and
+//! the GGUF layout is fixed on `finish`, so the output does not depend on scheduling.
+
+use super::{
+    create_strategy_for, quantize_rows, source_space_mse, weight_round_trip_mse,
+    QuantizationStrategy, StrategyType,
+};
+use crate::core::gptq::{permute_hessian, quantize_rows_q8k_gptq};
+use crate::core::validation::{
+    run_validation, validate_end_to_end, MonteCarloInputs, SourceColumns, TensorMetrics,
+    ValidationMetric,
+};
+use crate::core::{
+    check_quality_gates, skip_reason, BaselineComparison, CalibrationInputs, GateMode, GgufWriter,
+    HessianStore, ModelSource, OutputLayout, Q8KMetadata, QualityGateError, QuantFormat,
+    QuantizationConfig, QuantizationResult, SkipReason, SourceTensor, TensorReport, TensorTimings,
+};
+use crate::utils::permutation_hash;
+use anyhow::{bail, Context, Result};
+use candle_core::quantized::gguf_file::Value;
+use candle_core::quantized::k_quants::{
+    BlockQ2K, BlockQ3K, BlockQ4K, BlockQ5K, BlockQ6K, BlockQ8K,
+};
+use candle_core::quantized::GgmlType;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::{fs, thread, time::Instant};
+
+pub fn run_quantization(
+    input_path: &Path,
+    config: QuantizationConfig,
+) -> Result<QuantizationResult> {
+    let start_time = Instant::now();
+    if config.gptq.is_some() && config.format != QuantFormat::Q8K {
+        bail!(
+            "GPTQ is only supported for q8k output, not {}",
+            config.format.name()
+        );
+    }
+    if config.output_layout == OutputLayout::Gguf && config.format == QuantFormat::Q8K {
+        // Q8_K is a dot-product intermediate in ggml; neither candle nor llama.cpp load it.
+        bail!("gguf output cannot hold q8k tensors; pick q6k or a smaller format");
+    }
+    fs::create_dir_all(&config.output_dir)?;
+
+    let source = ModelSource::open(input_path)?;
+    let is_quantized = |t: &SourceTensor| skip_reason(t, &config.skip_patterns).is_none();
+    if let Some(budget) = config.memory_budget {
+        let over: Vec<String> = source
+            .tensors()
+            .iter()
+            .filter(|t| is_quantized(t))
+            .filter_map(|t| {
+                let need = tensor_working_set(t.shape[0], t.shape[1], &config);
+                (need > budget).then(|| format!("{} ({} MiB)", t.name, need >> 20))
+            })
+            .collect();
+        if !over.is_empty() {
+            bail!(
+                "{} tensor(s) exceed the memory budget of {} MiB: {}",
+                over.len(),
+                budget >> 20,
+                over.join(", ")
+            );
+        }
+    }
+
+    let strategy = if config.use_permutation {
+        Some(create_strategy_for(&config.strategy_type, config.format)?)
+    } else {
+        None
+    };
+
+    let hessians = match config.gptq {
+        Some(ref gptq) => Some(HessianStore::load(&gptq.calibration_path)?),
+        None => None,
+    };
+    let calibration_inputs = match config.validation.monte_carlo.inputs {
+        MonteCarloInputs::Calibration(ref path)
+            if config.validation.runs(ValidationMetric::MonteCarlo) =>
+        {
+            Some(CalibrationInputs::load(path)?)
+        }
+        _ => None,
+    };
+
+    let sink = match config.output_layout {
+        OutputLayout::PerTensor => TensorSink::Files(config.output_dir.clone()),
+        OutputLayout::Gguf => {
+            let stem = source.name();
+            let gguf_path = config.output_dir.join(format!("{stem}.gguf"));
+            let mut writer = GgufWriter::create(&gguf_path)?;
+            let source_meta = source.metadata();
+            if !source_meta.iter().any(|(key, _)| key == "general.name") {
+                writer.add_metadata("general.name", Value::String(stem.to_string()));
+            }
+            for (key, value) in source_meta {
+                writer.add_metadata(key, value);
+            }
+            writer.add_metadata("general.quantization_version", Value::U32(2));
+            writer.add_metadata(
+                "quantize.format",
+                Value::String(config.format.name().to_string()),
+            );
+            if let Some(ref strat) = strategy {
+                writer.add_metadata("quantize.strategy", Value::String(strat.name().to_string()));
+            }
+            println!("Output : {}", gguf_path.display());
+            TensorSink::Gguf(Mutex::new(writer))
+        }
+    };
+
+    println!("Tensors: {}", source.len());
+
+    let items = work_items(&source, strategy.as_deref());
+    let threads = config
+        .threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .clamp(1, items.len().max(1));
+    if threads > 1 {
+        println!("Threads: {threads}");
+    }
+
+    let pipeline = Pipeline {
+        config: &config,
+        source: &source,
+        strategy: strategy.as_deref(),
+        hessians: hessians.as_ref(),
+        calibration_inputs: calibration_inputs.as_ref(),
+        sink: &sink,
+        gate: MemoryGate::new(config.memory_budget),
+        deferred: Mutex::new(Vec::new()),
+    };
+    let mut tensors = pipeline.run(&items, threads)?;
+    let deferred = pipeline.deferred.into_inner().unwrap();
+    if let Some(gguf_path) = sink.finish(&source)? {
+        check_gguf_columns(&config, &source, &gguf_path, deferred, &mut tensors)?;
+    }
+
+    let quantized_count = tensors.iter().filter(|t| t.is_quantized()).count();
+    // Per-tensor breaches were logged as they happened; the model means are only known now.
+    let gate_failures = check_quality_gates(
+        &config.validation,
+        tensors.iter().filter_map(|t| t.metrics.as_ref()),
+    );
+    for failure in gate_failures.iter().filter(|f| f.tensor.is_none()) {
+        println!("[WARN] {failure}");
+    }
+
+    let result = QuantizationResult {
+        input: input_path.to_path_buf(),
+        format: config.format.name().to_string(),
+        strategy: strategy.as_ref().map(|s| s.name().to_string()),
+        threads,
+        quantized_tensors: quantized_count,
+        skipped_tensors: tensors.len() - quantized_count,
+        total_time_seconds: start_time.elapsed().as_secs_f32(),
+        tensors,
+        gate_failures,
+        peak_rss_bytes: crate::utils::peak_rss_bytes(),
+    };
+    // The report also covers runs that fail their gates, so offenders can be looked up.
+    if config.report {
+        result.write_reports(&config.output_dir)?;
+    }
+    if config.validation.gate == GateMode::Fail && !result.gate_failures.is_empty() {
+        return Err(QualityGateError {
+            failures: result.gate_failures,
+        }
+        .into());
+    }
+    Ok(result)
+}
+
+/// End-to-end check of GGUF tensors with permuted or scaled columns, gathering activations
+/// through the permutation and scales read back from the finished file at `gguf_path`. The
+/// blocks are compared with the source weights laid out as they were quantized.
+fn check_gguf_columns(
+    config: &QuantizationConfig,
+    source: &ModelSource,
+    gguf_path: &Path,
+    deferred: Vec<DeferredCheck>,
+    reports: &mut [TensorReport],
+) -> Result<()> {
+    if deferred.is_empty() {
+        return Ok(());
+    }
+    let container = ModelSource::open(gguf_path)?;
+    for check in deferred {
+        let find = |model: &ModelSource| {
+            model
+                .tensors()
+                .iter()
+                .find(|t| t.name == check.name)
+                .cloned()
+                .with_context(|| format!("{} missing from {}", check.name, model.name()))
+        };
+        let (stored, tensor) = (find(&container)?, find(source)?);
+        let e = match config.format {
+            QuantFormat::Q8K => stored_end_to_end::<BlockQ8K>,
+            QuantFormat::Q6K => stored_end_to_end::<BlockQ6K>,
+            QuantFormat::Q5K => stored_end_to_end::<BlockQ5K>,
+            QuantFormat::Q4K => stored_end_to_end::<BlockQ4K>,
+            QuantFormat::Q3K => stored_end_to_end::<BlockQ3K>,
+            QuantFormat::Q2K => stored_end_to_end::<BlockQ2K>,
+        }(config, source, &tensor, &container, &stored, &check)?;
+        if let Some(metrics) = reports
+            .iter_mut()
+            .find(|r| r.name == check.name)
+            .and_then(|r| r.metrics.as_mut())
+        {
+            metrics.end_to_end_error = Some(e);
+        }
+        source.release(&tensor);
+    }
+    Ok(())
+}
+
+fn stored_end_to_end<T: GgmlType>(
+    config: &QuantizationConfig,
+    source: &ModelSource,
+    tensor: &SourceTensor,
+    container: &ModelSource,
+    stored: &SourceTensor,
+    check: &DeferredCheck,
+) -> Result<f32> {
+    let k = tensor.shape[1];
+    let raw = container.raw(stored);
+    let mut blocks = vec![T::zeros(); raw.len() / std::mem::size_of::<T>()];
+    // SAFETY: k-quant blocks are plain integers and floats, valid for any bit pattern.
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            raw.as_ptr(),
+            blocks.as_mut_ptr() as *mut u8,
+            blocks.len() * std::mem::size_of::<T>(),
+        );
+    }
+    let weights = source.tensor_f32(tensor)?;
+    let mut quantized_from = vec![0f32; weights.len()];
+    for (src, dst) in weights
+        .chunks_exact(k)
+        .zip(quantized_from.chunks_exact_mut(k))
+    {
+        for (i, v) in dst.iter_mut().enumerate() {
+            let col = check.perm.as_ref().map_or(i, |p| p[i]);
+            *v = src[col] * check.scales.as_ref().map_or(1.0, |s| s[col]);
+        }
+    }
+    let perm = container.column_perm(&stored.name)?;
+    let scales = container.column_scales(&stored.name)?;
+    validate_end_to_end(
+        &config.validation,
+        &stored.name,
+        &quantized_from,
+        &blocks,
+        k,
+        &SourceColumns {
+            weights: &weights,
+            perm: perm.as_deref(),
+            scales: scales.as_deref(),
+        },
+    )
+}
+
+/// Group tensor indices into units of work: a tensor on its own, or every tensor sharing a
+/// strategy state key, in model order. Items are ordered by their first tensor.
+fn work_items(
+    source: &ModelSource,
+    strategy: Option<&dyn QuantizationStrategy>,
+) -> Vec<Vec<usize>> {
+    let mut items: Vec<Vec<usize>> = Vec::new();
+    let mut groups: HashMap<String, usize> = HashMap::new();
+    for (idx, tensor) in source.tensors().iter().enumerate() {
+        match strategy.and_then(|s| s.shared_state_key(&tensor.name)) {
+            Some(key) => match groups.get(&key) {
+                Some(&item) => items[item].push(idx),
+                None => {
+                    groups.insert(key, items.len());
+                    items.push(vec![idx]);
+                }
+            },
+            None => items.push(vec![idx]),
+        }
+    }
+    items
+}
+
+/// Rough peak bytes for quantizing one `rows x k` tensor: the f32 weights, the permuted
+/// copy, one strategy scratch buffer and the output blocks, plus for GPTQ the working copy
+/// of the weights, the decoded `k x k` f64 Hessian, its permuted copy and the factor.
+fn tensor_working_set(rows: usize, k: usize, config: &QuantizationConfig) -> u64 {
+    let elems = (rows * k) as u64;
+    let dtype = config.format.ggml_dtype();
+    let blocks = elems / dtype.block_size() as u64 * dtype.type_size() as u64;
+    let mut bytes = 3 * 4 * elems + blocks;
+    if config.gptq.is_some() {
+        bytes += 4 * elems + 3 * 8 * (k * k) as u64;
+    }
+    if config.compare_baseline {
+        // The unpermuted weights and a dequantized copy, and with GPTQ the blocks of the
+        // ordering quantized first
+        bytes += 2 * 4 * elems;
+        if config.gptq.is_some() {
+            bytes += blocks;
+        }
+    }
+    bytes
+}
+
+/// Weight MSE of `unpermuted` against that of `permuted` (quantized column `i` holds source
+/// column `perm[i]` times `scales[perm[i]]`), both in the source column space. With `gptq`, a
+/// Hessian in source column order and its dampening, both orderings are quantized with GPTQ
+/// and the blocks of the kept one are returned so they need not be computed again; otherwise
+/// both are plainly rounded to `format`.
+fn compare_baseline(
+    format: QuantFormat,
+    (rows, k): (usize, usize),
+    unpermuted: &[f32],
+    permuted: &[f32],
+    perm: Option<&[usize]>,
+    scales: Option<&[f32]>,
+    gptq: Option<(&[f64], f64)>,
+) -> Result<(BaselineComparison, Option<Vec<BlockQ8K>>)> {
+    let (unpermuted_mse, permuted_mse, blocks) = match gptq {
+        Some((hessian, damp_percent)) => {
+            let gptq_mse = |data: &[f32], perm, scales| -> Result<(f32, Vec<BlockQ8K>)> {
+                let h = permute_hessian(hessian, k, perm, scales);
+                let blocks = quantize_rows_q8k_gptq(rows, k, data, h, damp_percent)?;
+                let mut restored = vec![0f32; data.len()];
+                BlockQ8K::to_float(&blocks, &mut restored);
+                Ok((source_space_mse(k, data, &restored, perm, scales), blocks))
+            };
+            let (unpermuted_mse, unpermuted_blocks) = gptq_mse(unpermuted, None, None)?;
+            let (permuted_mse, permuted_blocks) = gptq_mse(permuted, perm, scales)?;
+            let kept = if permuted_mse <= unpermuted_mse {
+                permuted_blocks
+            } else {
+                unpermuted_blocks
+            };
+            (unpermuted_mse, permuted_mse, Some(kept))
+        }
+        None => (
+            weight_round_trip_mse(format, (rows, k), unpermuted, None, None)?,
+            weight_round_trip_mse(format, (rows, k), permuted, perm, scales)?,
+            None,
+        ),
+    };
+    let improvement = if unpermuted_mse > 0.0 {
+        1.0 - permuted_mse / unpermuted_mse
+    } else {
+        0.0
+    };
+    let comparison = BaselineComparison {
+        unpermuted_mse,
+        permuted_mse,
+        improvement,
+        permutation_kept: permuted_mse <= unpermuted_mse,
+        gptq: blocks.is_some(),
+    };
+    Ok((comparison, blocks))
+}
+
+/// Everything a worker needs to process one tensor.
+struct Pipeline<'a> {
+    config: &'a QuantizationConfig,
+    source: &'a ModelSource,
+    strategy: Option<&'a dyn QuantizationStrategy>,
+    hessians: Option<&'a HessianStore>,
+    calibration_inputs: Option<&'a CalibrationInputs>,
+    sink: &'a TensorSink,
+    gate: MemoryGate,
+    /// GGUF tensors whose end-to-end check waits for the finished file
+    deferred: Mutex<Vec<DeferredCheck>>,
+}
+
+/// The column layout a GGUF tensor was quantized with, to check against what the file holds.
+struct DeferredCheck {
+    name: String,
+    perm: Option<Vec<usize>>,
+    scales: Option<Vec<f32>>,
+}
+
+impl Pipeline<'_> {
+    /// Process all work items on `threads` workers; reports are returned in model order.
+    /// After the first failure no new items are started, and the error of the earliest
+    /// failing tensor is returned.
+    fn run(&self, items: &[Vec<usize>], threads: usize) -> Result<Vec<TensorReport>> {
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let worker = || {
+            let mut done = Vec::new();
+            while !failed.load(Ordering::Relaxed) {
+                let Some(item) = items.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                    break;
+                };
+                for &idx in item {
+                    let outcome = self.process(&self.source.tensors()[idx]);
+                    let is_err = outcome.is_err();
+                    done.push((idx, outcome));
+                    if is_err {
+                        failed.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                }
+            }
+            done
+        };
+        let mut done: Vec<(usize, Result<TensorReport>)> = thread::scope(|s| {
+            let handles: Vec<_> = (0..threads).map(|_| s.spawn(worker)).collect();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().expect("quantization worker panicked"))
+                .collect()
+        });
+        done.sort_by_key(|(idx, _)| *idx);
+        done.into_iter().map(|(_, outcome)| outcome).collect()
+    }
+
+    fn process(&self, tensor: &SourceTensor) -> Result<TensorReport> {
+        let (config, source, sink) = (self.config, self.source, self.sink);
+        let name = &tensor.name;
+        let mut report = TensorReport {
+            name: name.clone(),
+            shape: tensor.shape.clone(),
+            source_dtype: tensor.dtype.map(|d| format!("{d:?}")),
+            skip_reason: skip_reason(tensor, &config.skip_patterns),
+            format: None,
+            strategy: None,
+            skipped_candidates: Vec::new(),
+            perm_hash: None,
+            scaled: false,
+            timings: None,
+            baseline: None,
+            metrics: None,
+        };
+
+        if let Some(reason) = &report.skip_reason {
+            if let SkipReason::InnerDim { .. } = reason {
+                println!("skip ({reason}): {name} {:?}", tensor.shape);
+            }
+            sink.passthrough(source, tensor)?;
+            source.release(tensor);
+            return Ok(report);
+        }
+
+        let (rows, k) = (tensor.shape[0], tensor.shape[1]);
+        let _permit = self.gate.acquire(tensor_working_set(rows, k, config));
+        println!("quantizing {name} ({rows} x {k})");
+        let started = Instant::now();
+        let mut timings = TensorTimings::default();
+
+        // Load weights to f32, dequantizing GGUF block formats
+        let data_f32 = source.tensor_f32(tensor)?;
+        source.release(tensor);
+        timings.load_seconds = started.elapsed().as_secs_f32();
+
+        // Apply permutation strategy if enabled; the unpermuted copy is dropped here unless
+        // the baseline comparison needs it
+        let strategy_start = Instant::now();
+        let (mut data_for_quant, mut maybe_perm, unpermuted) = match self.strategy {
+            Some(strat) => {
+                let (permuted, perm) = strat.apply_permutation(&data_f32, rows, k, name)?;
+                (permuted, perm, config.compare_baseline.then_some(data_f32))
+            }
+            None => (data_f32, None, None),
+        };
+        timings.strategy_seconds = strategy_start.elapsed().as_secs_f32();
+        let mut maybe_scales = self
+            .strategy
+            .and_then(|strat| strat.take_column_scales(name));
+        if let Some(strat) = self.strategy {
+            report.skipped_candidates = strat
+                .take_skipped_candidates(name)
+                .into_iter()
+                .map(str::to_string)
+                .collect();
+        }
+        let strategy_name = self
+            .strategy
+            .map(|strat| strat.take_selected_strategy(name).unwrap_or(strat.name()));
+
+        // GPTQ error compensation when a Hessian is available
+        let hessian = match self.hessians {
+            Some(store) if config.format == QuantFormat::Q8K => store.hessian(name, k)?,
+            _ => None,
+        };
+        let gptq = hessian
+            .as_deref()
+            .zip(config.gptq.as_ref().map(|g| g.damp_percent));
+
+        let mut baseline_blocks = None;
+        if let Some(unpermuted) = unpermuted {
+            let baseline_start = Instant::now();
+            let (baseline, blocks) = compare_baseline(
+                config.format,
+                (rows, k),
+                &unpermuted,
+                &data_for_quant,
+                maybe_perm.as_deref(),
+                maybe_scales.as_deref(),
+                gptq,
+            )?;
+            if blocks.is_some() {
+                timings.quantize_seconds = baseline_start.elapsed().as_secs_f32();
+            }
+            baseline_blocks = blocks;
+            println!(
+                "  baseline: {} MSE {:.6e} permuted vs {:.6e} unpermuted ({:+.2}%){}",
+                if baseline.gptq { "GPTQ" } else { "rounding" },
+                baseline.permuted_mse,
+                baseline.unpermuted_mse,
+                baseline.improvement * 100.0,
+                if baseline.permutation_kept {
+                    ""
+                } else {
+                    ", permutation dropped"
+                }
+            );
+            if !baseline.permutation_kept {
+                data_for_quant = unpermuted;
+                maybe_perm = None;
+                maybe_scales = None;
+            }
+            report.baseline = Some(baseline);
+        }
+        let strategy_name =
+            strategy_name.filter(|_| report.baseline.as_ref().is_none_or(|b| b.permutation_kept));
+
+        // Quantize to the target block format, validate and write quantized data
+        let out = TensorOut {
+            tensor,
+            rows,
+            k,
+            perm: maybe_perm.as_deref(),
+            scales: maybe_scales.as_deref(),
+            strategy: strategy_name,
+        };
+        let data = &data_for_quant;
+        let t = &mut timings;
+        let metrics = match config.format {
+            QuantFormat::Q8K => {
+                let quantize_start = Instant::now();
+                let blocks = match (baseline_blocks, gptq) {
+                    // Already quantized by the baseline comparison
+                    (Some(blocks), _) => blocks,
+                    (None, Some((h, damp_percent))) => {
+                        let h =
+                            permute_hessian(h, k, maybe_perm.as_deref(), maybe_scales.as_deref());
+                        quantize_rows_q8k_gptq(rows, k, data, h, damp_percent)?
+                    }
+                    (None, None) => {
+                        if config.gptq.is_some() {
+                            println!("  no hessian for {name}, using plain rounding");
+                        }
+                        quantize_rows::<BlockQ8K>(rows, k, data)?
+                    }
+                };
+                t.quantize_seconds += quantize_start.elapsed().as_secs_f32();
+                self.validate_and_write(out, data, &blocks, t)?
+            }
+            QuantFormat::Q6K => self.quantize_and_write::<BlockQ6K>(out, data, t)?,
+            QuantFormat::Q5K => self.quantize_and_write::<BlockQ5K>(out, data, t)?,
+            QuantFormat::Q4K => self.quantize_and_write::<BlockQ4K>(out, data, t)?,
+            QuantFormat::Q3K => self.quantize_and_write::<BlockQ3K>(out, data, t)?,
+            QuantFormat::Q2K => self.quantize_and_write::<BlockQ2K>(out, data, t)?,
+        };
+
+        // Log the results
+        let summary = metrics.summary();
+        if !summary.is_empty() {
+            println!("  {summary}");
+        }
+        if let (Some(mse_matmul), Some(mse_direct)) = (metrics.matmul_mse, metrics.direct_mse) {
+            let diff = (mse_matmul - mse_direct).abs();
+            if diff > 1e-6 {
+                println!("    [INFO] Validation methods differ by {:.8e}", diff);
+            }
+        }
+        for (metric, v, threshold) in metrics.exceeded(&config.validation) {
+            let side = if metric.higher_is_better() {
+                "below"
+            } else {
+                "above"
+            };
+            println!(
+                "    [WARN] {} {:.6e} {side} threshold {:.1e} - quantization may be lossy",
+                metric.name(),
+                v,
+                threshold
+            );
+        }
+
+        if let Some(perm) = maybe_perm {
+            report.perm_hash = Some(format!("{:016x}", permutation_hash(&perm)));
+        }
+        report.scaled = maybe_scales.is_some();
+
+        timings.total_seconds = started.elapsed().as_secs_f32();
+        report.format = Some(config.format.name().to_string());
+        report.strategy = strategy_name.map(str::to_string);
+        report.timings = Some(timings);
+        report.metrics = Some(metrics);
+        Ok(report)
+    }
+
+    /// Quantize `data`, validate it against the blocks and write them out.
+    fn quantize_and_write<T: GgmlType>(
+        &self,
+        out: TensorOut<'_>,
+        data: &[f32],
+        timings: &mut TensorTimings,
+    ) -> Result<TensorMetrics> {
+        let start = Instant::now();
+        let blocks = quantize_rows::<T>(out.rows, out.k, data)?;
+        timings.quantize_seconds = start.elapsed().as_secs_f32();
+        self.validate_and_write(out, data, &blocks, timings)
+    }
+
+    /// Write the blocks with their permutation and scales, then validate them against the
+    /// source weights in their original column order when a permutation or scales are in
+    /// play. The end-to-end check gathers activations through the permutation and scales as
+    /// read back from the output, so a sidecar that does not match the blocks fails the
+    /// tensor. GGUF output can only be read back after `finish`; its end-to-end check is
+    /// left to `check_gguf_columns`.
+    fn validate_and_write<T: GgmlType>(
+        &self,
+        out: TensorOut<'_>,
+        data: &[f32],
+        blocks: &[T],
+        timings: &mut TensorTimings,
+    ) -> Result<TensorMetrics> {
+        let name = out.tensor.name.as_str();
+        self.sink
+            .write_blocks(&out, &self.q8k_metadata(&out), blocks)?;
+        if let Some(perm) = out.perm {
+            self.sink.write_perm(name, perm)?;
+        }
+        if let Some(scales) = out.scales {
+            self.sink.write_scales(name, scales)?;
+        }
+
+        let start = Instant::now();
+        let mut validation = Cow::Borrowed(&self.config.validation);
+        let remapped = out.perm.is_some() || out.scales.is_some();
+        let stored = self.sink.stored_columns(name)?;
+        if stored.is_none() && remapped && validation.runs(ValidationMetric::EndToEnd) {
+            validation
+                .to_mut()
+                .metrics
+                .retain(|&m| m != ValidationMetric::EndToEnd);
+            self.deferred.lock().unwrap().push(DeferredCheck {
+                name: name.to_string(),
+                perm: out.perm.map(<[usize]>::to_vec),
+                scales: out.scales.map(<[f32]>::to_vec),
+            });
+        }
+        let (perm, scales) = match stored {
+            Some((ref perm, ref scales)) => (perm.as_deref(), scales.as_deref()),
+            None => (out.perm, out.scales),
+        };
+        let source_weights = if remapped
+            && (validation.runs(ValidationMetric::EndToEnd)
+                || validation.runs(ValidationMetric::MonteCarlo))
+        {
+            let weights = self.source.tensor_f32(out.tensor)?;
+            self.source.release(out.tensor);
+            Some(weights)
+        } else {
+            None
+        };
+        let columns = source_weights.as_deref().map(|weights| SourceColumns {
+            weights,
+            perm,
+            scales,
+        });
+        let metrics = run_validation(
+            &validation,
+            name,
+            data,
+            blocks,
+            out.k,
+            columns.as_ref(),
+            self.calibration_inputs,
+        )?;
+        timings.validation_seconds = start.elapsed().as_secs_f32();
+        Ok(metrics)
+    }
+
+    /// Provenance recorded in the `.q8k` header. The parameters of an `Auto` run are those
+    /// of the candidate it picked for the tensor.
+    fn q8k_metadata(&self, out: &TensorOut<'_>) -> Q8KMetadata {
+        let strategy_params = out
+            .strategy
+            .map(|selected| match self.config.strategy_type {
+                StrategyType::Auto { ref candidates, .. } => candidates
+                    .iter()
+                    .find(|c| c.name() == selected)
+                    .map(StrategyType::params)
+                    .unwrap_or_default(),
+                ref strategy_type => strategy_type.params(),
+            });
+        Q8KMetadata {
+            orig_dtype: out.tensor.dtype,
+            orig_shape: out.tensor.shape.clone(),
+            strategy: out.strategy.map(str::to_string),
+            strategy_params: strategy_params.unwrap_or_default(),
+        }
+    }
+}
+
+/// Caps the summed working set of tensors in flight at the memory budget. `run_quantization`
+/// rejects any tensor over the budget before starting, so every tensor fits on its own.
+struct MemoryGate {
+    budget: Option<u64>,
+    in_flight: Mutex<u64>,
+    released: Condvar,
+}
+
+struct MemoryPermit<'a> {
+    gate: &'a MemoryGate,
+    bytes: u64,
+}
+
+impl MemoryGate {
+    fn new(budget: Option<u64>) -> Self {
+        Self {
+            budget,
+            in_flight: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self, bytes: u64) -> MemoryPermit<'_> {
+        if let Some(budget) = self.budget {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            while *in_flight + bytes > budget {
+                in_flight = self.released.wait(in_flight).unwrap();
+            }
+            *in_flight += bytes;
+        }
+        MemoryPermit { gate: self, bytes }
+    }
+}
+
+impl Drop for MemoryPermit<'_> {
+    fn drop(&mut self) {
+        if self.gate.budget.is_some() {
+            *self.gate.in_flight.lock().unwrap() -= self.bytes;
+            self.gate.released.notify_all();
+        }
+    }
+}
+
+/// One quantized tensor on its way out: its source, shape and the column layout of `data`.
+struct TensorOut<'a> {
+    tensor: &'a SourceTensor,
+    rows: usize,
+    k: usize,
+    perm: Option<&'a [usize]>,
+    scales: Option<&'a [f32]>,
+    /// Strategy that ordered the columns; `None` when they are in source order
+    strategy: Option<&'a str>,
+}
+
+/// Permutation and scales of a tensor as read back from the output.
+type StoredColumns = (Option<Vec<usize>>, Option<Vec<f32>>);
+
+/// Where quantized tensors go: `.q8k` files with sidecars, or one GGUF file.
+enum TensorSink {
+    Files(PathBuf),
+    Gguf(Mutex<GgufWriter>),
+}
+
+impl TensorSink {
+    fn q8k_path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{}.q8k", name))
+    }
+
+    /// Write the blocks of `out`. Per-tensor files also embed its permutation and `meta` in
+    /// the header; GGUF output records the permutation as metadata in `write_perm`.
+    fn write_blocks<T: GgmlType>(
+        &self,
+        out: &TensorOut<'_>,
+        meta: &Q8KMetadata,
+        blocks: &[T],
+    ) -> Result<()> {
+        let name = out.tensor.name.as_str();
+        match self {
+            TensorSink::Files(dir) => {
+                let path = Self::q8k_path(dir, name);
+                // Sidecars left by an earlier run would be read back as this tensor's layout
+                for ext in ["perm", "scale"] {
+                    let sidecar = path.with_extension(ext);
+                    if sidecar.exists() {
+                        fs::remove_file(&sidecar)?;
+                    }
+                }
+                crate::core::io::write_quantized_with(
+                    &path, out.rows, out.k, blocks, meta, out.perm,
+                )
+            }
+            TensorSink::Gguf(w) => w
+                .lock()
+                .unwrap()
+                .add_quantized(name, out.rows, out.k, blocks),
+        }
+    }
+
+    fn write_perm(&self, name: &str, perm: &[usize]) -> Result<()> {
+        match self {
+            // Embedded in the `.q8k` header by `write_blocks`; a `.perm` sidecar would be a
+            // second copy to keep in sync
+            TensorSink::Files(_) => Ok(()),
+            TensorSink::Gguf(w) => {
+                let values = perm.iter().map(|&p| Value::U32(p as u32)).collect();
+                w.lock().unwrap().add_metadata(
+                    format!("{}{name}", crate::core::gguf::GGUF_PERM_PREFIX),
+                    Value::Array(values),
+                );
+                Ok(())
+            }
+        }
+    }
+
+    fn write_scales(&self, name: &str, scales: &[f32]) -> Result<()> {
+        match self {
+            TensorSink::Files(dir) => {
+                crate::core::io::write_scales(&Self::q8k_path(dir, name), scales)
+            }
+            TensorSink::Gguf(w) => {
+                let values = scales.iter().map(|&s| Value::F32(s)).collect();
+                w.lock().unwrap().add_metadata(
+                    format!("{}{name}", crate::core::gguf::GGUF_SCALE_PREFIX),
+                    Value::Array(values),
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Keep a tensor that is not quantized. Per-tensor output leaves these in the source
+    /// model; GGUF output stores them alongside the quantized ones.
+    fn passthrough(&self, source: &ModelSource, tensor: &SourceTensor) -> Result<()> {
+        match self {
+            TensorSink::Files(_) => Ok(()),
+            TensorSink::Gguf(w) => {
+                let Some(dtype) = tensor.dtype else {
+                    println!("  not stored in gguf: {} has no ggml type", tensor.name);
+                    return Ok(());
+                };
+                w.lock()
+                    .unwrap()
+                    .add_raw(&tensor.name, &tensor.shape, dtype, source.raw(tensor))
+            }
+        }
+    }
+
+    /// The permutation and scales of `name` as read back from the output; `None` for GGUF,
+    /// which is only readable after `finish`.
+    fn stored_columns(&self, name: &str) -> Result<Option<StoredColumns>> {
+        match self {
+            TensorSink::Files(dir) => {
+                let path = Self::q8k_path(dir, name);
+                Ok(Some((
+                    crate::core::io::load_perm(&path)?,
+                    crate::core::io::load_scales(&path)?,
+                )))
+            }
+            TensorSink::Gguf(_) => Ok(None),
+        }
+    }
+
+    /// Complete the output; GGUF tensors are laid out in model order. Returns the path of
+    /// the GGUF file.
+    fn finish(self, source: &ModelSource) -> Result<Option<PathBuf>> {
+        match self {
+            TensorSink::Files(_) => Ok(None),
+            TensorSink::Gguf(w) => {
+                let order: HashMap<&str, usize> = source
+                    .tensors()
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, t)| (t.name.as_str(), idx))
+                    .collect();
+                let mut w = w.into_inner().unwrap();
+                w.sort_tensors_by_key(|name| order.get(name).copied());
+                let path = w.path().to_path_buf();
+                w.finish()?;
+                Ok(Some(path))
+            }
+        }
+    }
+}
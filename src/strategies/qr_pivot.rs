@@ -29,21 +29,21 @@ impl QRPivotStrategy {
         }
         //Partial QR optimization
         let qr_steps = match k {
-            k if k <= 64 => k,              // Tiny matrices: full QR
-            k if k <= 256 => (k * 3) / 4,   // Small matrices: 75% QR
-            k if k <= 512 => k / 2,         // Medium matrices: 50% QR
-            k if k <= 1024 => k / 3,        // Large matrices: 33% QR
-            k if k <= 2048 => k / 4,        // Very large matrices: 25% QR (512 cols)
-            _ => (k / 8).max(256).min(512), // Huge matrices: 1/8 QR, 256-512 range
+            k if k <= 64 => k,            // Tiny matrices: full QR
+            k if k <= 256 => (k * 3) / 4, // Small matrices: 75% QR
+            k if k <= 512 => k / 2,       // Medium matrices: 50% QR
+            k if k <= 1024 => k / 3,      // Large matrices: 33% QR
+            k if k <= 2048 => k / 4,      // Very large matrices: 25% QR (512 cols)
+            _ => (k / 8).clamp(256, 512), // Huge matrices: 1/8 QR, 256-512 range
         };
         // QR with column pivoting - ONLY for qr_steps columns
         for step in 0..qr_steps.min(rows) {
             // Find column with largest remaining norm
             let mut max_norm_sq = col_norms_sq[step];
             let mut max_col = step;
-            for j in (step + 1)..k {
-                if col_norms_sq[j] > max_norm_sq {
-                    max_norm_sq = col_norms_sq[j];
+            for (j, &norm_sq) in col_norms_sq.iter().enumerate().skip(step + 1) {
+                if norm_sq > max_norm_sq {
+                    max_norm_sq = norm_sq;
                     max_col = j;
                 }
             }
@@ -60,11 +60,13 @@ impl QRPivotStrategy {
             // Apply Householder reflection to current column
             self.apply_householder_step(&mut a, &mut col_norms_sq, rows, k, step)?;
         }
-        // optimization: For remaining columns, just sort by L2 norm (much faster)
+        // optimization: For remaining columns, just sort by L2 norm (much faster).
+        // Pivoting swapped columns into these positions, so sort the column indices
+        // they hold, not the positions themselves
         if qr_steps < k {
             println!("  Sorting remaining {} columns by L2 norm", k - qr_steps);
             let mut remaining: Vec<(usize, f32)> =
-                (qr_steps..k).map(|j| (j, col_norms_sq[j])).collect();
+                (qr_steps..k).map(|j| (perm[j], col_norms_sq[j])).collect();
             remaining.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
             for (i, (orig_idx, _)) in remaining.iter().enumerate() {
                 perm[qr_steps + i] = *orig_idx;
@@ -100,7 +102,6 @@ impl QRPivotStrategy {
         }
 
         // Compute Householder vector for column 'step', starting from row 'step'
-        let col_start = step;
         let n_rows_remaining = rows - step;
 
         if n_rows_remaining == 0 {
@@ -217,3 +218,31 @@ impl QuantizationStrategy for QRPivotStrategy {
         "QRPivot"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_qr_permutation_is_a_bijection() {
+        // k = 512 runs 256 QR steps and sorts the other 256 columns by norm
+        let (rows, k) = (32, 512);
+        let data: Vec<f32> = (0..rows * k)
+            .map(|i| ((i * 7919 % 1009) as f32 / 1009.0 - 0.5) * (1 + i % k % 13) as f32)
+            .collect();
+        let strategy = QRPivotStrategy::new(1e-6);
+        let (permuted, perm) = strategy.apply_permutation(&data, rows, k, "t").unwrap();
+        let perm = perm.unwrap();
+
+        let mut sorted = perm.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..k).collect::<Vec<_>>());
+        // The tail actually moved columns, so the check covers the L2-sorted part
+        assert_ne!(perm[256..], (256..k).collect::<Vec<_>>()[..]);
+        for r in 0..rows {
+            for (i, &j) in perm.iter().enumerate() {
+                assert_eq!(permuted[r * k + i], data[r * k + j]);
+            }
+        }
+    }
+}
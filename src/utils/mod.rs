@@ -1,11 +1,19 @@
 //! Utility functions for quantization operations.
 
+pub mod memory;
 pub mod permutation;
 pub mod tensor_ops;
 
-pub use permutation::{apply_column_permutation, build_column_permutation, column_l2_norms};
-pub use tensor_ops::tensor_to_f32;
+pub use memory::peak_rss_bytes;
+pub use permutation::{
+    apply_column_permutation, build_column_permutation, column_l2_norms,
+    hungarian_block_assignment, permutation_hash,
+};
+pub use tensor_ops::{f32_to_tensor, tensor_to_f32};
 
+/// Name-only check of whether a tensor is quantized. It cannot see the shape, so it also
+/// accepts 1-D and badly sized tensors that `skip_reason` passes through.
+#[deprecated(note = "use core::skip_reason")]
 pub fn is_target_weight(name: &str, skip_patterns: &[String]) -> bool {
     if !name.ends_with(".weight") {
         return false;
@@ -1,5 +1,7 @@
 //! Permutation utility functions.
 
+use anyhow::{bail, Result};
+
 pub fn column_l2_norms(rows: usize, k: usize, data: &[f32]) -> Vec<f32> {
     let mut sums: Vec<f64> = vec![0.0; k];
     for r in 0..rows {
@@ -33,3 +35,176 @@ pub fn apply_column_permutation(rows: usize, k: usize, data: &[f32], perm: &[usi
     }
     out
 }
+
+/// Assign `n` items to `blocks` slots of `capacity` seats each at minimum total cost.
+///
+/// `cost` is row-major `n x blocks`. This is the Hungarian algorithm (successive shortest
+/// augmenting paths) on the `n x (blocks * capacity)` matrix whose seats inside one block
+/// share a cost column; working on the collapsed block graph keeps it tractable for wide
+/// tensors. Returns the block index of every item, or an error if rounding in the costs
+/// leaves a cycle in an augmenting path.
+pub fn hungarian_block_assignment(
+    cost: &[f64],
+    n: usize,
+    blocks: usize,
+    capacity: usize,
+) -> Result<Vec<usize>> {
+    assert_eq!(cost.len(), n * blocks, "cost matrix must be n x blocks");
+    assert!(n <= blocks * capacity, "not enough seats for {n} items");
+
+    let mut assign = vec![usize::MAX; n];
+    let mut members: Vec<Vec<usize>> = vec![Vec::with_capacity(capacity); blocks];
+
+    // Block-to-block move edges: cheapest member of `from` to relocate into `to`.
+    let mut move_cost = vec![f64::INFINITY; blocks * blocks];
+    let mut move_item = vec![usize::MAX; blocks * blocks];
+    let mut dist = vec![0f64; blocks];
+    let mut pred = vec![usize::MAX; blocks];
+
+    for item in 0..n {
+        move_cost.fill(f64::INFINITY);
+        for (from, list) in members.iter().enumerate() {
+            for &j in list {
+                let base = cost[j * blocks + from];
+                for to in 0..blocks {
+                    let c = cost[j * blocks + to] - base;
+                    if to != from && c < move_cost[from * blocks + to] {
+                        move_cost[from * blocks + to] = c;
+                        move_item[from * blocks + to] = j;
+                    }
+                }
+            }
+        }
+
+        // Bellman-Ford from the new item; the current assignment is optimal for the items
+        // placed so far, so the residual graph has no negative cycles up to rounding.
+        dist.copy_from_slice(&cost[item * blocks..(item + 1) * blocks]);
+        pred.fill(usize::MAX);
+        for _ in 1..blocks {
+            let mut changed = false;
+            for from in 0..blocks {
+                if members[from].is_empty() {
+                    continue;
+                }
+                for to in 0..blocks {
+                    let c = dist[from] + move_cost[from * blocks + to];
+                    if c < dist[to] - 1e-12 {
+                        dist[to] = c;
+                        pred[to] = from;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let mut target = usize::MAX;
+        for b in 0..blocks {
+            if members[b].len() < capacity && (target == usize::MAX || dist[b] < dist[target]) {
+                target = b;
+            }
+        }
+
+        // Walk the augmenting path back, shifting one member along each edge. A shortest
+        // path visits every block at most once; a longer walk is a near-zero cycle.
+        let mut to = target;
+        let mut steps = 0;
+        while pred[to] != usize::MAX {
+            steps += 1;
+            if steps > blocks {
+                bail!("augmenting path for item {item} does not end after {blocks} blocks");
+            }
+            let from = pred[to];
+            let j = move_item[from * blocks + to];
+            members[from].retain(|&m| m != j);
+            members[to].push(j);
+            assign[j] = to;
+            to = from;
+        }
+        members[to].push(item);
+        assign[item] = to;
+    }
+    Ok(assign)
+}
+
+/// FNV-1a hash of a permutation, to tell orderings apart in reports.
+pub fn permutation_hash(perm: &[usize]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &p in perm {
+        for byte in (p as u32).to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Cheapest assignment of `n` items to `blocks` blocks of `capacity`, by enumeration.
+    fn brute_force(cost: &[f64], n: usize, blocks: usize, capacity: usize) -> f64 {
+        let mut best = f64::INFINITY;
+        let mut assign = vec![0usize; n];
+        loop {
+            let mut load = vec![0usize; blocks];
+            assign.iter().for_each(|&b| load[b] += 1);
+            if load.iter().all(|&l| l <= capacity) {
+                let c = assign
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &b)| cost[i * blocks + b]);
+                best = best.min(c.sum());
+            }
+            // Next assignment in base `blocks`
+            let Some(i) = assign.iter().position(|&b| b + 1 < blocks) else {
+                return best;
+            };
+            assign[..i].fill(0);
+            assign[i] += 1;
+        }
+    }
+
+    #[test]
+    fn block_assignment_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for blocks in 1..=3 {
+            for capacity in 1..=3 {
+                for n in 1..=(blocks * capacity).min(6) {
+                    for _ in 0..5 {
+                        let cost: Vec<f64> = (0..n * blocks)
+                            .map(|_| rng.random_range(-1.0..1.0))
+                            .collect();
+                        let assign =
+                            hungarian_block_assignment(&cost, n, blocks, capacity).unwrap();
+
+                        assert_eq!(assign.len(), n);
+                        let mut load = vec![0usize; blocks];
+                        for &b in &assign {
+                            assert!(b < blocks, "item left unassigned");
+                            load[b] += 1;
+                        }
+                        assert!(load.iter().all(|&l| l <= capacity), "{load:?} > {capacity}");
+
+                        let total: f64 = assign
+                            .iter()
+                            .enumerate()
+                            .map(|(i, &b)| cost[i * blocks + b])
+                            .sum();
+                        let optimal = brute_force(&cost, n, blocks, capacity);
+                        assert!(
+                            (total - optimal).abs() < 1e-9,
+                            "n = {n}, blocks = {blocks}, capacity = {capacity}: \
+                             {total} vs optimal {optimal}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
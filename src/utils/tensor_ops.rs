@@ -27,3 +27,19 @@ pub fn tensor_to_f32(bytes: &[u8], dtype: Dtype) -> Result<Vec<f32>> {
         other => bail!("unsupported dtype {other:?}"),
     })
 }
+
+/// Encode f32 values as little-endian bytes of a float `dtype`, the inverse of `tensor_to_f32`.
+pub fn f32_to_tensor(values: &[f32], dtype: Dtype) -> Result<Vec<u8>> {
+    Ok(match dtype {
+        Dtype::F32 => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        Dtype::F16 => values
+            .iter()
+            .flat_map(|&v| f16::from_f32(v).to_bits().to_le_bytes())
+            .collect(),
+        Dtype::BF16 => values
+            .iter()
+            .flat_map(|&v| bf16::from_f32(v).to_bits().to_le_bytes())
+            .collect(),
+        other => bail!("unsupported dtype {other:?}"),
+    })
+}